@@ -1,56 +1,189 @@
+use std::any::Any;
+use std::fmt;
+
 use crate::dram::*;
 use crate::trap::*;
 use crate::plic::*;
 use crate::clint::*;
+use crate::uart::*;
 
 pub const CLINT_BASE: u64 = 0x200_0000;
 pub const CLINT_SIZE: u64 = 0x10000;
 pub const PLIC_BASE: u64 = 0xc00_0000;
 pub const PLIC_SIZE: u64 = 0x4000000;
+pub const UART_BASE: u64 = 0x1000_0000;
+pub const UART_SIZE: u64 = 0x100;
 
-pub trait Device {
-    fn load(&self, addr: u64, size: u64) -> Result<u64, Exception>;
+// A memory-mapped device. Accesses arrive with their full physical address
+// and a size in bits.
+pub trait Device: Any {
+    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception>;
     fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception>;
+
+    // Advances the device by one instruction.
+    fn tick(&mut self) {}
+
+    // The level of the device's interrupt line, routed to the PLIC source
+    // the device is connected to.
+    fn interrupt_line(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug)]
+pub enum BusError {
+    EmptyRegion(String),
+    Overlap { name: String, existing: String },
+    DuplicateName(String),
+    UnknownDevice(String),
 }
 
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BusError::EmptyRegion(name) => write!(f, "region {} is empty or wraps around the address space", name),
+            BusError::Overlap { name, existing } => write!(f, "region {} overlaps {}", name, existing),
+            BusError::DuplicateName(name) => write!(f, "a region named {} is already mapped", name),
+            BusError::UnknownDevice(name) => write!(f, "no region named {}", name),
+        }
+    }
+}
+
+impl std::error::Error for BusError {}
+
+// A device mapped at [base, base + size).
+pub struct Region {
+    pub name: String,
+    pub base: u64,
+    pub size: u64,
+    // the PLIC source the device's interrupt line drives, if any
+    pub irq: Option<u32>,
+    pub device: Box<dyn Device>,
+}
+
+impl Region {
+    fn contains(&self, addr: u64) -> bool {
+        (self.base..self.base + self.size).contains(&addr)
+    }
+
+    fn downcast_mut<T: Device>(&mut self) -> Option<&mut T> {
+        (self.device.as_mut() as &mut dyn Any).downcast_mut::<T>()
+    }
+}
+
+// The physical address space: devices sorted by base address.
 pub struct Bus{
-    dram: Dram,
-    plic: Plic,
-    clint: Clint,
+    regions: Vec<Region>,
+    // index of the PLIC region, which interrupt lines are routed to
+    plic: Option<usize>,
 }
 
 impl Bus{
-    pub fn new(binary: Vec<u8>) -> Self{
-        Self { 
-            dram: Dram::new(binary),
-            plic: Plic::new(),
-            clint: Clint::new(),
+    // The standard devices plus `memory_size` bytes of RAM at DRAM_BASE.
+    pub fn new(memory_size: u64) -> Self{
+        let mut bus = Self { regions: Vec::new(), plic: None };
+        bus.add_device("clint", CLINT_BASE, CLINT_SIZE, Box::new(Clint::new())).unwrap();
+        bus.add_device("plic", PLIC_BASE, PLIC_SIZE, Box::new(Plic::new())).unwrap();
+        bus.add_device("uart", UART_BASE, UART_SIZE, Box::new(Uart::new())).unwrap();
+        bus.add_device("dram", DRAM_BASE, memory_size, Box::new(Dram::new(memory_size))).unwrap();
+        bus.connect_irq("uart", UART_IRQ).unwrap();
+        bus
+    }
+
+    // Maps `device` at [base, base + size). The region must not overlap any
+    // already mapped.
+    pub fn add_device(&mut self, name: &str, base: u64, size: u64, device: Box<dyn Device>) -> Result<(), BusError> {
+        if size == 0 || base.checked_add(size).is_none() {
+            return Err(BusError::EmptyRegion(name.to_string()));
+        }
+        if self.regions.iter().any(|region| region.name == name) {
+            return Err(BusError::DuplicateName(name.to_string()));
         }
+        if let Some(existing) = self.regions.iter().find(|region| base < region.base + region.size && region.base < base + size) {
+            return Err(BusError::Overlap {
+                name: name.to_string(),
+                existing: existing.name.clone(),
+            });
+        }
+        let index = self.regions.partition_point(|region| region.base < base);
+        self.regions.insert(index, Region {
+            name: name.to_string(),
+            base,
+            size,
+            irq: None,
+            device,
+        });
+        self.plic = self.regions.iter().position(|region| (region.device.as_ref() as &dyn Any).is::<Plic>());
+        Ok(())
+    }
+
+    // Wires the interrupt line of the device named `name` to PLIC source `irq`.
+    pub fn connect_irq(&mut self, name: &str, irq: u32) -> Result<(), BusError> {
+        let region = self.regions.iter_mut().find(|region| region.name == name);
+        let region = region.ok_or_else(|| BusError::UnknownDevice(name.to_string()))?;
+        region.irq = Some(irq);
+        Ok(())
     }
 
-    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
-        if CLINT_BASE <= addr && addr < CLINT_BASE + CLINT_SIZE {
-            return self.clint.load(addr, size)
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    // The first mapped device of type `T`.
+    pub fn device<T: Device>(&self) -> Option<&T> {
+        self.regions.iter().find_map(|region| (region.device.as_ref() as &dyn Any).downcast_ref::<T>())
+    }
+
+    pub fn device_mut<T: Device>(&mut self) -> Option<&mut T> {
+        self.regions.iter_mut().find_map(|region| region.downcast_mut::<T>())
+    }
+
+    // Prints the address map, one region per line.
+    pub fn dump_map(&self) {
+        for region in &self.regions {
+            let irq = region.irq.map_or(String::new(), |irq| format!(" (irq {})", irq));
+            println!("{:#018x}-{:#018x} {}{}", region.base, region.base + region.size - 1, region.name, irq);
         }
-        if PLIC_BASE <= addr && addr < PLIC_BASE + PLIC_SIZE {
-            return self.plic.load(addr, size)
+    }
+
+    // Advances the devices by one instruction and routes their interrupt lines to the PLIC.
+    pub fn tick(&mut self) {
+        for region in self.regions.iter_mut() {
+            region.device.tick();
         }
-        if addr >= DRAM_BASE{
-            return self.dram.load(addr, size)
+        for i in 0..self.regions.len() {
+            if let Some(irq) = self.regions[i].irq {
+                let level = self.regions[i].device.interrupt_line();
+                self.set_irq(irq, level);
+            }
         }
-        Err(Exception::LoadAccessFault)
     }
-    
-    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        if CLINT_BASE <= addr && addr < CLINT_BASE + CLINT_SIZE {
-            return self.clint.store(addr, size, value)
+
+    // Drives PLIC source `irq`, for interrupt lines not tied to a mapped
+    // region. Does nothing if no PLIC is mapped.
+    pub fn set_irq(&mut self, irq: u32, level: bool) {
+        if let Some(plic) = self.plic.and_then(|plic| self.regions[plic].downcast_mut::<Plic>()) {
+            plic.set_irq(irq, level);
         }
-        if PLIC_BASE <= addr && addr < PLIC_BASE + PLIC_SIZE {
-            return self.plic.store(addr, size, value)
+    }
+
+    fn region_mut(&mut self, addr: u64) -> Option<&mut Region> {
+        let index = self.regions.partition_point(|region| region.base <= addr);
+        let region = self.regions.get_mut(index.checked_sub(1)?)?;
+        region.contains(addr).then_some(region)
+    }
+
+    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        match self.region_mut(addr) {
+            Some(region) => region.device.load(addr, size),
+            None => Err(Exception::LoadAccessFault(addr)),
         }
-        if addr >= DRAM_BASE{
-            return self.dram.store(addr, size, value)
+    }
+
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        match self.region_mut(addr) {
+            Some(region) => region.device.store(addr, size, value),
+            None => Err(Exception::StoreAMOAccessFault(addr)),
         }
-        Err(Exception::StoreAMOAccessFault)
     }
-}
\ No newline at end of file
+}
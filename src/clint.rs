@@ -1,53 +1,218 @@
+use std::time::{Duration, Instant};
+
 use crate::trap::*;
 use crate::bus::*;
 
+// The core-local interruptor: a software interrupt bit (msip) and a timer
+// compare register (mtimecmp) for each hart, and the shared mtime counter.
+// Every register can be accessed whole or, for 32-bit software, as two
+// 32-bit halves.
+
+pub const CLINT_MSIP: u64 = CLINT_BASE;
 pub const CLINT_MTIMECMP: u64 = CLINT_BASE + 0x4000;
 pub const CLINT_MTIME: u64 = CLINT_BASE + 0xbff8;
 
+// What drives mtime.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Clock {
+    // one tick per instruction, so runs are deterministic
+    Instructions,
+    // host wall-clock time at `frequency` ticks per second
+    WallClock { frequency: u64 },
+}
+
 pub struct Clint {
+    clock: Clock,
+    // mtime itself when counting instructions; with a wall clock, the value
+    // mtime had at `epoch`
     mtime: u64,
-    mtimecmp: u64,
+    epoch: Instant,
+    msip: Vec<u32>,
+    mtimecmp: Vec<u64>,
+}
+
+// Reads `size` bits at byte `offset` of a 64-bit register.
+fn read_part(reg: u64, offset: u64, size: u64) -> u64 {
+    let value = reg >> (8 * offset);
+    if size == 64 { value } else { value & 0xffff_ffff }
+}
+
+// Replaces `size` bits at byte `offset` of a 64-bit register.
+fn write_part(reg: u64, offset: u64, size: u64, value: u64) -> u64 {
+    if size == 64 {
+        return value;
+    }
+    let shift = 8 * offset;
+    (reg & !(0xffff_ffff << shift)) | ((value & 0xffff_ffff) << shift)
 }
 
 impl Device for Clint {
-    fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
-        if size == 64 {
-            match addr {
-                CLINT_MTIMECMP => {
-                    return Ok(self.mtimecmp);
-                }
-                CLINT_MTIME => {
-                    return Ok(self.mtime);
-                }
-                _ => {
-                    return Ok(0);
-                }
+    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        // whole registers or aligned 32-bit halves only
+        if !(size == 32 || size == 64) || !addr.is_multiple_of(size / 8) {
+            return Err(Exception::LoadAccessFault(addr));
+        }
+        let harts = self.harts() as u64;
+        let value = match addr {
+            CLINT_MSIP..CLINT_MTIMECMP if size == 32 => {
+                let hart = ((addr - CLINT_MSIP) / 4) as usize;
+                self.msip.get(hart).copied().unwrap_or(0) as u64
+            }
+            _ if (CLINT_MTIMECMP..CLINT_MTIMECMP + 8 * harts).contains(&addr) => {
+                let hart = ((addr - CLINT_MTIMECMP) / 8) as usize;
+                read_part(self.mtimecmp[hart], addr % 8, size)
             }
-        }   
-        Err(Exception::LoadAccessFault)
+            _ if (CLINT_MTIME..CLINT_MTIME + 8).contains(&addr) => read_part(self.mtime(), addr - CLINT_MTIME, size),
+            _ => 0,
+        };
+        Ok(value)
     }
+
     fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        if size == 64 {
-            match addr {
-                CLINT_MTIMECMP => {
-                    self.mtimecmp = value;
-                }
-                CLINT_MTIME => {
-                    self.mtime = value
+        if !(size == 32 || size == 64) || !addr.is_multiple_of(size / 8) {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
+        let harts = self.harts() as u64;
+        match addr {
+            CLINT_MSIP..CLINT_MTIMECMP if size == 32 => {
+                let hart = ((addr - CLINT_MSIP) / 4) as usize;
+                if let Some(msip) = self.msip.get_mut(hart) {
+                    *msip = value as u32 & 1;
                 }
-                _ => {}
             }
-            return Ok(());
-        }   
-        Err(Exception::StoreAMOAccessFault)
+            _ if (CLINT_MTIMECMP..CLINT_MTIMECMP + 8 * harts).contains(&addr) => {
+                let hart = ((addr - CLINT_MTIMECMP) / 8) as usize;
+                self.mtimecmp[hart] = write_part(self.mtimecmp[hart], addr % 8, size, value);
+            }
+            _ if (CLINT_MTIME..CLINT_MTIME + 8).contains(&addr) => {
+                let mtime = write_part(self.mtime(), addr - CLINT_MTIME, size, value);
+                self.set_mtime(mtime);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Counts an instruction when mtime runs on instructions.
+    fn tick(&mut self) {
+        if self.clock == Clock::Instructions {
+            self.mtime = self.mtime.wrapping_add(1);
+        }
+    }
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl Clint{
+    // A CLINT for a single hart, counting instructions.
     pub fn new() -> Self {
+        Self::with_harts(1, Clock::Instructions)
+    }
+
+    pub fn with_harts(harts: usize, clock: Clock) -> Self {
         Self {
+            clock,
             mtime: 0,
-            mtimecmp: 0,
+            epoch: Instant::now(),
+            msip: vec![0; harts],
+            mtimecmp: vec![0; harts],
         }
     }
-}
\ No newline at end of file
+
+    pub fn harts(&self) -> usize {
+        self.mtimecmp.len()
+    }
+
+    pub fn clock(&self) -> Clock {
+        self.clock
+    }
+
+    // Switches what drives mtime; mtime carries on from its current value.
+    pub fn set_clock(&mut self, clock: Clock) {
+        let mtime = self.mtime();
+        self.clock = clock;
+        self.set_mtime(mtime);
+    }
+
+    pub fn mtime(&self) -> u64 {
+        match self.clock {
+            Clock::Instructions => self.mtime,
+            Clock::WallClock { frequency } => {
+                let ticks = self.epoch.elapsed().as_nanos() * frequency as u128 / 1_000_000_000;
+                self.mtime.wrapping_add(ticks as u64)
+            }
+        }
+    }
+
+    pub fn set_mtime(&mut self, mtime: u64) {
+        self.mtime = mtime;
+        self.epoch = Instant::now();
+    }
+
+    pub fn mtimecmp(&self, hart: usize) -> Option<u64> {
+        self.mtimecmp.get(hart).copied()
+    }
+
+    // Lets an idle hart skip ahead to a timer deadline. Counting
+    // instructions, mtime jumps there at once; on a wall clock, returns how
+    // long the host has to wait for it. None if the deadline has passed, or
+    // is u64::MAX, which software uses to turn the timer off: jumping there
+    // would only wrap mtime on the next tick.
+    pub fn skip_to(&mut self, deadline: u64) -> Option<Duration> {
+        let mtime = self.mtime();
+        if mtime >= deadline || deadline == u64::MAX {
+            return None;
+        }
+        match self.clock {
+            Clock::Instructions => {
+                self.mtime = deadline;
+                Some(Duration::ZERO)
+            }
+            Clock::WallClock { frequency } => {
+                let nanos = (deadline - mtime) as u128 * 1_000_000_000 / frequency.max(1) as u128;
+                Some(Duration::from_nanos(nanos.min(u64::MAX as u128) as u64))
+            }
+        }
+    }
+
+    pub fn timer_pending(&self, hart: usize) -> bool {
+        self.mtimecmp.get(hart).is_some_and(|mtimecmp| self.mtime() >= *mtimecmp)
+    }
+
+    pub fn software_pending(&self, hart: usize) -> bool {
+        self.msip.get(hart).is_some_and(|msip| *msip != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skip_to_reaches_a_deadline() {
+        let mut clint = Clint::new();
+        assert_eq!(clint.skip_to(5000), Some(Duration::ZERO));
+        assert_eq!(clint.mtime(), 5000);
+        assert!(clint.timer_pending(0));
+        // a deadline already passed leaves mtime alone
+        assert_eq!(clint.skip_to(10), None);
+        assert_eq!(clint.mtime(), 5000);
+        // however far off a finite deadline is
+        assert_eq!(clint.skip_to(u64::MAX - 1), Some(Duration::ZERO));
+        assert_eq!(clint.mtime(), u64::MAX - 1);
+    }
+
+    #[test]
+    fn skip_to_ignores_a_timer_that_is_off() {
+        let mut clint = Clint::new();
+        assert_eq!(clint.skip_to(u64::MAX), None);
+        assert_eq!(clint.mtime(), 0);
+        clint.set_mtime(u64::MAX - 10);
+        assert_eq!(clint.skip_to(u64::MAX), None);
+        assert_eq!(clint.mtime(), u64::MAX - 10);
+    }
+}
@@ -0,0 +1,227 @@
+use crate::trap::*;
+
+// Encoders for the 32-bit instruction formats the compressed forms expand into.
+fn r_type(opcode: u64, rd: u64, funct3: u64, rs1: u64, rs2: u64, funct7: u64) -> u64 {
+    opcode | (rd << 7) | (funct3 << 12) | (rs1 << 15) | (rs2 << 20) | (funct7 << 25)
+}
+
+fn i_type(opcode: u64, rd: u64, funct3: u64, rs1: u64, imm: u64) -> u64 {
+    opcode | (rd << 7) | (funct3 << 12) | (rs1 << 15) | ((imm & 0xfff) << 20)
+}
+
+fn s_type(opcode: u64, funct3: u64, rs1: u64, rs2: u64, imm: u64) -> u64 {
+    opcode | ((imm & 0x1f) << 7) | (funct3 << 12) | (rs1 << 15) | (rs2 << 20) | (((imm >> 5) & 0x7f) << 25)
+}
+
+fn b_type(funct3: u64, rs1: u64, rs2: u64, imm: u64) -> u64 {
+    0x63 | (((imm >> 11) & 1) << 7)
+        | (((imm >> 1) & 0xf) << 8)
+        | (funct3 << 12)
+        | (rs1 << 15)
+        | (rs2 << 20)
+        | (((imm >> 5) & 0x3f) << 25)
+        | (((imm >> 12) & 1) << 31)
+}
+
+fn j_type(rd: u64, imm: u64) -> u64 {
+    0x6f | (rd << 7)
+        | (((imm >> 12) & 0xff) << 12)
+        | (((imm >> 11) & 1) << 20)
+        | (((imm >> 1) & 0x3ff) << 21)
+        | (((imm >> 20) & 1) << 31)
+}
+
+fn bit(inst: u64, pos: u32) -> u64 {
+    (inst >> pos) & 1
+}
+
+fn bits(inst: u64, hi: u32, lo: u32) -> u64 {
+    (inst >> lo) & ((1 << (hi - lo + 1)) - 1)
+}
+
+// Sign-extends the low `width` bits of `value`.
+fn sext(value: u64, width: u32) -> u64 {
+    (((value << (64 - width)) as i64) >> (64 - width)) as u64
+}
+
+pub fn is_compressed(instruction: u64) -> bool {
+    instruction & 0b11 != 0b11
+}
+
+// Expands a 16-bit RV64C instruction into its equivalent 32-bit encoding.
+pub fn expand(inst: u64) -> Result<u64, Exception> {
+    let op = inst & 0b11;
+    let funct3 = bits(inst, 15, 13);
+    // full-width register fields
+    let rd = bits(inst, 11, 7);
+    let rs2 = bits(inst, 6, 2);
+    // 3-bit register fields map to x8-x15
+    let rdp = bits(inst, 4, 2) + 8;
+    let rs1p = bits(inst, 9, 7) + 8;
+    // 6-bit immediate shared by c.addi, c.li, c.andi, ...
+    let imm6 = sext((bit(inst, 12) << 5) | bits(inst, 6, 2), 6);
+    let shamt = (bit(inst, 12) << 5) | bits(inst, 6, 2);
+
+    let expanded = match (op, funct3) {
+        //c.addi4spn
+        (0b00, 0b000) => {
+            let imm = (bits(inst, 12, 11) << 4)
+                | (bits(inst, 10, 7) << 6)
+                | (bit(inst, 6) << 2)
+                | (bit(inst, 5) << 3);
+            if imm == 0 {
+                return Err(Exception::IllegalInstruction(inst));
+            }
+            i_type(0x13, rdp, 0x0, 2, imm)
+        }
+        //c.fld
+        (0b00, 0b001) => i_type(0x07, rdp, 0x3, rs1p, (bits(inst, 12, 10) << 3) | (bits(inst, 6, 5) << 6)),
+        //c.lw
+        (0b00, 0b010) => {
+            let imm = (bits(inst, 12, 10) << 3) | (bit(inst, 6) << 2) | (bit(inst, 5) << 6);
+            i_type(0x03, rdp, 0x2, rs1p, imm)
+        }
+        //c.ld
+        (0b00, 0b011) => i_type(0x03, rdp, 0x3, rs1p, (bits(inst, 12, 10) << 3) | (bits(inst, 6, 5) << 6)),
+        //c.fsd
+        (0b00, 0b101) => s_type(0x27, 0x3, rs1p, rdp, (bits(inst, 12, 10) << 3) | (bits(inst, 6, 5) << 6)),
+        //c.sw
+        (0b00, 0b110) => {
+            let imm = (bits(inst, 12, 10) << 3) | (bit(inst, 6) << 2) | (bit(inst, 5) << 6);
+            s_type(0x23, 0x2, rs1p, rdp, imm)
+        }
+        //c.sd
+        (0b00, 0b111) => s_type(0x23, 0x3, rs1p, rdp, (bits(inst, 12, 10) << 3) | (bits(inst, 6, 5) << 6)),
+        //c.addi (c.nop when rd == 0)
+        (0b01, 0b000) => i_type(0x13, rd, 0x0, rd, imm6),
+        //c.addiw
+        (0b01, 0b001) => {
+            if rd == 0 {
+                return Err(Exception::IllegalInstruction(inst));
+            }
+            i_type(0x1b, rd, 0x0, rd, imm6)
+        }
+        //c.li
+        (0b01, 0b010) => i_type(0x13, rd, 0x0, 0, imm6),
+        (0b01, 0b011) => {
+            if rd == 2 {
+                //c.addi16sp
+                let imm = sext(
+                    (bit(inst, 12) << 9)
+                        | (bit(inst, 6) << 4)
+                        | (bit(inst, 5) << 6)
+                        | (bits(inst, 4, 3) << 7)
+                        | (bit(inst, 2) << 5),
+                    10,
+                );
+                if imm == 0 {
+                    return Err(Exception::IllegalInstruction(inst));
+                }
+                i_type(0x13, 2, 0x0, 2, imm)
+            } else {
+                //c.lui
+                if imm6 == 0 {
+                    return Err(Exception::IllegalInstruction(inst));
+                }
+                0x37 | (rd << 7) | ((imm6 & 0xfffff) << 12)
+            }
+        }
+        (0b01, 0b100) => {
+            let rd = rs1p;
+            match bits(inst, 11, 10) {
+                //c.srli
+                0b00 => i_type(0x13, rd, 0x5, rd, shamt),
+                //c.srai
+                0b01 => i_type(0x13, rd, 0x5, rd, shamt | 0x400),
+                //c.andi
+                0b10 => i_type(0x13, rd, 0x7, rd, imm6),
+                _ => match (bit(inst, 12), bits(inst, 6, 5)) {
+                    //c.sub
+                    (0, 0b00) => r_type(0x33, rd, 0x0, rd, rdp, 0x20),
+                    //c.xor
+                    (0, 0b01) => r_type(0x33, rd, 0x4, rd, rdp, 0x00),
+                    //c.or
+                    (0, 0b10) => r_type(0x33, rd, 0x6, rd, rdp, 0x00),
+                    //c.and
+                    (0, 0b11) => r_type(0x33, rd, 0x7, rd, rdp, 0x00),
+                    //c.subw
+                    (1, 0b00) => r_type(0x3b, rd, 0x0, rd, rdp, 0x20),
+                    //c.addw
+                    (1, 0b01) => r_type(0x3b, rd, 0x0, rd, rdp, 0x00),
+                    _ => return Err(Exception::IllegalInstruction(inst)),
+                },
+            }
+        }
+        //c.j
+        (0b01, 0b101) => {
+            let imm = sext(
+                (bit(inst, 12) << 11)
+                    | (bit(inst, 11) << 4)
+                    | (bits(inst, 10, 9) << 8)
+                    | (bit(inst, 8) << 10)
+                    | (bit(inst, 7) << 6)
+                    | (bit(inst, 6) << 7)
+                    | (bits(inst, 5, 3) << 1)
+                    | (bit(inst, 2) << 5),
+                12,
+            );
+            j_type(0, imm)
+        }
+        //c.beqz, c.bnez
+        (0b01, 0b110) | (0b01, 0b111) => {
+            let imm = sext(
+                (bit(inst, 12) << 8)
+                    | (bits(inst, 11, 10) << 3)
+                    | (bits(inst, 6, 5) << 6)
+                    | (bits(inst, 4, 3) << 1)
+                    | (bit(inst, 2) << 5),
+                9,
+            );
+            b_type(funct3 & 1, rs1p, 0, imm)
+        }
+        //c.slli
+        (0b10, 0b000) => i_type(0x13, rd, 0x1, rd, shamt),
+        //c.fldsp
+        (0b10, 0b001) => {
+            let imm = (bit(inst, 12) << 5) | (bits(inst, 6, 5) << 3) | (bits(inst, 4, 2) << 6);
+            i_type(0x07, rd, 0x3, 2, imm)
+        }
+        //c.lwsp
+        (0b10, 0b010) => {
+            if rd == 0 {
+                return Err(Exception::IllegalInstruction(inst));
+            }
+            let imm = (bit(inst, 12) << 5) | (bits(inst, 6, 4) << 2) | (bits(inst, 3, 2) << 6);
+            i_type(0x03, rd, 0x2, 2, imm)
+        }
+        //c.ldsp
+        (0b10, 0b011) => {
+            if rd == 0 {
+                return Err(Exception::IllegalInstruction(inst));
+            }
+            let imm = (bit(inst, 12) << 5) | (bits(inst, 6, 5) << 3) | (bits(inst, 4, 2) << 6);
+            i_type(0x03, rd, 0x3, 2, imm)
+        }
+        (0b10, 0b100) => match (bit(inst, 12), rd, rs2) {
+            //c.jr
+            (0, 0, 0) => return Err(Exception::IllegalInstruction(inst)),
+            (0, _, 0) => i_type(0x67, 0, 0x0, rd, 0),
+            //c.mv
+            (0, _, _) => r_type(0x33, rd, 0x0, 0, rs2, 0x00),
+            //c.ebreak
+            (1, 0, 0) => 0x00100073,
+            //c.jalr
+            (1, _, 0) => i_type(0x67, 1, 0x0, rd, 0),
+            //c.add
+            _ => r_type(0x33, rd, 0x0, rd, rs2, 0x00),
+        },
+        //c.fsdsp
+        (0b10, 0b101) => s_type(0x27, 0x3, 2, rs2, (bits(inst, 12, 10) << 3) | (bits(inst, 9, 7) << 6)),
+        //c.swsp
+        (0b10, 0b110) => s_type(0x23, 0x2, 2, rs2, (bits(inst, 12, 9) << 2) | (bits(inst, 8, 7) << 6)),
+        //c.sdsp
+        (0b10, 0b111) => s_type(0x23, 0x3, 2, rs2, (bits(inst, 12, 10) << 3) | (bits(inst, 9, 7) << 6)),
+        _ => return Err(Exception::IllegalInstruction(inst)),
+    };
+    Ok(expanded)
+}
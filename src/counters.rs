@@ -0,0 +1,101 @@
+// The Zicntr and Zihpm counters. mcycle and minstret advance once per step
+// and retired instruction; cycle, time and instret are their read-only
+// user-level shadows, with time reading the CLINT's mtime. The hpmcounters
+// exist but count no events and always read as zero.
+//
+// Below M-mode a counter is readable only if its bit is set in mcounteren,
+// and from U-mode also in scounteren.
+//
+// With Sstc, S-mode gets its own timer: once menvcfg.STCE is set, STIP is
+// raised whenever time >= stimecmp, without a trip through M-mode.
+
+use crate::clint::*;
+use crate::cpu::*;
+use crate::trap::*;
+
+//Counter CSRs
+pub const CYCLE: usize = 0xc00;
+pub const TIME: usize = 0xc01;
+pub const INSTRET: usize = 0xc02;
+pub const HPMCOUNTER3: usize = 0xc03;
+pub const HPMCOUNTER31: usize = 0xc1f;
+pub const SCOUNTEREN: usize = 0x106;
+pub const STIMECMP: usize = 0x14d;
+pub const MENVCFG: usize = 0x30a;
+pub const MCOUNTINHIBIT: usize = 0x320;
+pub const MHPMEVENT3: usize = 0x323;
+pub const MHPMEVENT31: usize = 0x33f;
+pub const MCYCLE: usize = 0xb00;
+pub const MINSTRET: usize = 0xb02;
+pub const MHPMCOUNTER3: usize = 0xb03;
+pub const MHPMCOUNTER31: usize = 0xb1f;
+
+//mcountinhibit bits
+pub const COUNTINHIBIT_CY: u64 = 1 << 0;
+pub const COUNTINHIBIT_IR: u64 = 1 << 2;
+
+//menvcfg fields
+pub const MENVCFG_STCE: u64 = 1 << 63;
+
+impl Cpu {
+    // Reads one of the user-level counters, cycle through hpmcounter31.
+    pub fn load_counter(&self, csr: usize) -> u64 {
+        match csr {
+            CYCLE => self.csregs[MCYCLE],
+            TIME => self.time(),
+            INSTRET => self.csregs[MINSTRET],
+            _ => 0,
+        }
+    }
+
+    pub fn time(&self) -> u64 {
+        self.bus.device::<Clint>().map_or(0, |clint| clint.mtime())
+    }
+
+    pub fn sstc_enabled(&self) -> bool {
+        self.csregs[MENVCFG] & MENVCFG_STCE != 0
+    }
+
+    // Whether the Sstc timer is raising STIP.
+    pub fn supervisor_timer_pending(&self) -> bool {
+        self.sstc_enabled() && self.time() >= self.csregs[STIMECMP]
+    }
+
+    // stimecmp is reachable below M-mode only with menvcfg.STCE set and, as
+    // it is compared with time, mcounteren.TM.
+    pub fn check_stimecmp_access(&self, instruction: u64) -> Result<(), Exception> {
+        let tm = 1 << (TIME - CYCLE);
+        if self.curr_mode != Mode::Machine && (!self.sstc_enabled() || self.csregs[MCOUNTEREN] & tm == 0) {
+            return Err(Exception::IllegalInstruction(instruction));
+        }
+        Ok(())
+    }
+
+    // Counts a step that retired an instruction if `retired` is set. A
+    // counter the instruction wrote keeps the written value, as if the write
+    // happened after the increment.
+    pub fn advance_counters(&mut self, before: (u64, u64), retired: bool) {
+        let inhibit = self.csregs[MCOUNTINHIBIT];
+        if inhibit & COUNTINHIBIT_CY == 0 && self.csregs[MCYCLE] == before.0 {
+            self.csregs[MCYCLE] = before.0.wrapping_add(1);
+        }
+        if retired && inhibit & COUNTINHIBIT_IR == 0 && self.csregs[MINSTRET] == before.1 {
+            self.csregs[MINSTRET] = before.1.wrapping_add(1);
+        }
+    }
+
+    // Reading a user-level counter below M-mode needs its bit in mcounteren,
+    // and from U-mode in scounteren as well.
+    pub fn check_counter_access(&self, csr: usize, instruction: u64) -> Result<(), Exception> {
+        let bit = 1 << (csr - CYCLE);
+        let allowed = match self.curr_mode {
+            Mode::Machine => true,
+            Mode::Supervisor => self.csregs[MCOUNTEREN] & bit != 0,
+            Mode::User => self.csregs[MCOUNTEREN] & self.csregs[SCOUNTEREN] & bit != 0,
+        };
+        if !allowed {
+            return Err(Exception::IllegalInstruction(instruction));
+        }
+        Ok(())
+    }
+}
@@ -1,10 +1,32 @@
 #![allow(dead_code, unused_variables)]
+use std::thread;
+use std::time::Duration;
+
 use crate::bus::*;
-use crate::dram::{DRAM_SIZE, DRAM_BASE};
+use crate::clint::*;
+use crate::counters::*;
+use crate::plic::*;
+use crate::dram::DRAM_BASE;
 use crate::trap::*;
+use crate::compressed::*;
+use crate::fpu::*;
+use crate::mmu::*;
+use crate::pmp::*;
+use crate::privilege::*;
+use crate::decode;
+use crate::trace::*;
+
+//User-level CSRs
+pub const FFLAGS: usize = 0x001;
+pub const FRM: usize = 0x002;
+pub const FCSR: usize = 0x003;
 
 //Machine-level CSRs 
+pub const MVENDORID: usize = 0xf11;
+pub const MARCHID: usize = 0xf12;
+pub const MIMPID: usize = 0xf13;
 pub const MSTATUS: usize = 0x300;
+pub const MISA: usize = 0x301;
 pub const MIE: usize = 0x304;
 pub const MTVEC: usize = 0x305;
 pub const MHARTID: usize = 0xf14;
@@ -28,6 +50,58 @@ pub const STVAL: usize = 0x143;
 pub const SIP: usize = 0x144;
 pub const SATP: usize = 0x180;
 
+//mip/mie bits
+pub const MIP_SSIP: u64 = 1 << 1;
+pub const MIP_MSIP: u64 = 1 << 3;
+pub const MIP_STIP: u64 = 1 << 5;
+pub const MIP_MTIP: u64 = 1 << 7;
+pub const MIP_SEIP: u64 = 1 << 9;
+pub const MIP_MEIP: u64 = 1 << 11;
+
+//mstatus fields
+pub const MSTATUS_SIE: u64 = 1 << 1;
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_FS: u64 = 0b11 << 13;
+pub const MSTATUS_UXL: u64 = 0b11 << 32;
+pub const MSTATUS_SXL: u64 = 0b11 << 34;
+pub const MSTATUS_SD: u64 = 1 << 63;
+
+// mstatus bits software can write; SD and the XLEN fields are read-only
+const MSTATUS_WRITABLE: u64 = MSTATUS_SIE
+    | MSTATUS_MIE
+    | MSTATUS_SPIE
+    | MSTATUS_MPIE
+    | MSTATUS_SPP
+    | MSTATUS_MPP
+    | MSTATUS_FS
+    | MSTATUS_MPRV
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_TVM
+    | MSTATUS_TW
+    | MSTATUS_TSR;
+// the part of mstatus visible through sstatus
+pub const SSTATUS_MASK: u64 =
+    MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_FS | MSTATUS_SUM | MSTATUS_MXR | MSTATUS_UXL | MSTATUS_SD;
+
+// RV64 with the I, M, A, F, D, C, S and U extensions
+pub const MISA_VALUE: u64 = (2 << 62)
+    | (1 << 0)
+    | (1 << 2)
+    | (1 << 3)
+    | (1 << 5)
+    | (1 << 8)
+    | (1 << 12)
+    | (1 << 18)
+    | (1 << 20);
+// interrupts that can be delegated to S-mode, and the exceptions (all but an
+// ecall from M-mode and the reserved causes)
+const MIDELEG_WRITABLE: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+const MEDELEG_WRITABLE: u64 = 0xb3ff;
+const MIE_WRITABLE: u64 = MIP_SSIP | MIP_MSIP | MIP_STIP | MIP_MTIP | MIP_SEIP | MIP_MEIP;
+// the timer and external bits of mip follow the CLINT and PLIC
+const MIP_WRITABLE: u64 = MIP_SSIP | MIP_STIP;
+
 #[derive(Debug, PartialEq, PartialOrd, Eq, Copy, Clone)]
 pub enum Mode{
     User = 0x0,
@@ -35,70 +109,466 @@ pub enum Mode{
     Machine = 0x3
 }
 
+// What a load or store that is not naturally aligned does. AMOs and LR/SC
+// always trap.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum MisalignedPolicy {
+    // split the access into bytes, each translated on its own
+    Emulate,
+    // raise an address-misaligned exception so M-mode firmware can emulate it
+    Trap,
+}
+
 pub struct Cpu{
     pub registers: [u64; 32],
+    pub fregs: [u64; 32],
     pub pc: u64,
     pub bus: Bus,
     pub csregs: [u64; 4096],
     pub curr_mode: Mode,
+    pub reservation: Option<u64>,
+    // set to log every executed instruction
+    pub trace: Option<Trace>,
+    pub misaligned: MisalignedPolicy,
+    // set by WFI; the hart fetches nothing until an interrupt is pending
+    pub waiting: bool,
+    pub pmp: Pmp,
 }
 
 impl Cpu{
-    pub fn new(binary: Vec<u8>) -> Self{
+    pub fn new(memory_size: u64) -> Self{
         let mut regs = [0; 32];
-        regs[2] = DRAM_BASE + DRAM_SIZE;
+        regs[2] = DRAM_BASE + memory_size;
+        let mut csregs = [0; 4096];
+        // start with the FPU in the Initial state so FP code runs without
+        // setup; U-mode and S-mode are 64-bit, as UXL and SXL report
+        csregs[MSTATUS] = (1 << 13) | (2 << 32) | (2 << 34);
         Self {
             registers: regs,
+            fregs: [0; 32],
             pc: DRAM_BASE,
-            bus: Bus::new(binary),
-            csregs: [0; 4096],
+            bus: Bus::new(memory_size),
+            csregs,
             curr_mode: Mode::Machine,
+            reservation: None,
+            trace: None,
+            misaligned: MisalignedPolicy::Emulate,
+            waiting: false,
+            pmp: Pmp::new(),
         }
     }   
 
+    // Fetches the instruction at pc, reading a second halfword only when the
+    // first one does not mark a compressed instruction. Each halfword is
+    // translated on its own since a 32-bit instruction may straddle a page.
     pub fn fetch(&mut self) -> Result<u64, Exception> {
-        match self.bus.load(self.pc, 32) {
-            Ok(inst) => Ok(inst),
-            Err(_e) => Err(Exception::InstructionAccessFault),
+        if self.pc & 1 != 0 {
+            return Err(Exception::InstructionAddressMisaligned(self.pc));
+        }
+        let paddr = self.translate(self.pc, AccessType::Instruction)?;
+        self.check_pmp(paddr, 16, AccessType::Instruction, self.pc)?;
+        let low = self.bus.load(paddr, 16).map_err(|_| AccessType::Instruction.access_fault(self.pc))?;
+        if is_compressed(low) {
+            return Ok(low);
         }
+        // a fault on the second halfword reports that halfword's address
+        let vaddr = self.pc.wrapping_add(2);
+        let paddr = self.translate(vaddr, AccessType::Instruction)?;
+        self.check_pmp(paddr, 16, AccessType::Instruction, vaddr)?;
+        let high = self.bus.load(paddr, 16).map_err(|_| AccessType::Instruction.access_fault(vaddr))?;
+        Ok(low | (high << 16))
     }
 
-    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception>{
-        self.bus.load(addr, size)
+    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception>{
+        let value = if addr.is_multiple_of(size / 8) {
+            let paddr = self.translate(addr, AccessType::Load)?;
+            self.check_pmp(paddr, size, AccessType::Load, addr)?;
+            self.bus.load(paddr, size).map_err(|_| AccessType::Load.access_fault(addr))?
+        } else {
+            self.load_misaligned(addr, size)?
+        };
+        if let Some(trace) = self.trace.as_mut() {
+            trace.load(addr);
+        }
+        Ok(value)
     }
 
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception>{
-        self.bus.store(addr, size, value)
+        if addr.is_multiple_of(size / 8) {
+            let paddr = self.translate(addr, AccessType::Store)?;
+            self.check_pmp(paddr, size, AccessType::Store, addr)?;
+            self.break_reservation(paddr, size);
+            self.bus.store(paddr, size, value).map_err(|_| AccessType::Store.access_fault(addr))?;
+        } else {
+            self.store_misaligned(addr, size, value)?;
+        }
+        if let Some(trace) = self.trace.as_mut() {
+            trace.store(addr, size, value);
+        }
+        Ok(())
+    }
+
+    // Loads a little-endian value one byte at a time, since its bytes may lie
+    // in different pages.
+    fn load_misaligned(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if self.misaligned == MisalignedPolicy::Trap {
+            return Err(Exception::LoadAddressMisaligned(addr));
+        }
+        let mut value = 0;
+        for i in 0..size / 8 {
+            let vaddr = addr.wrapping_add(i);
+            let paddr = self.translate(vaddr, AccessType::Load)?;
+            self.check_pmp(paddr, 8, AccessType::Load, vaddr)?;
+            let byte = self.bus.load(paddr, 8).map_err(|_| AccessType::Load.access_fault(vaddr))?;
+            value |= byte << (8 * i);
+        }
+        Ok(value)
+    }
+
+    // Stores a value one byte at a time. Every byte is translated and checked
+    // before any is written so a page or PMP fault leaves memory untouched.
+    fn store_misaligned(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if self.misaligned == MisalignedPolicy::Trap {
+            return Err(Exception::StoreAMOAddressMisaligned(addr));
+        }
+        let mut paddrs = [0; 8];
+        for i in 0..size / 8 {
+            let vaddr = addr.wrapping_add(i);
+            paddrs[i as usize] = self.translate(vaddr, AccessType::Store)?;
+            self.check_pmp(paddrs[i as usize], 8, AccessType::Store, vaddr)?;
+        }
+        for i in 0..size / 8 {
+            let paddr = paddrs[i as usize];
+            self.break_reservation(paddr, 8);
+            self.bus
+                .store(paddr, 8, value >> (8 * i))
+                .map_err(|_| AccessType::Store.access_fault(addr.wrapping_add(i)))?;
+        }
+        Ok(())
+    }
+
+    // Any store overlapping the reserved doubleword breaks an outstanding LR.
+    fn break_reservation(&mut self, paddr: u64, size: u64) {
+        if let Some(reserved) = self.reservation {
+            let granule = reserved & !7;
+            if paddr < granule + 8 && paddr + size / 8 > granule {
+                self.reservation = None;
+            }
+        }
+    }
+
+    // Latches the interrupt lines driven by the CLINT and PLIC into mip.
+    pub fn update_mip(&mut self) {
+        let mut mip = self.csregs[MIP] & !(MIP_MSIP | MIP_MTIP | MIP_SEIP | MIP_MEIP);
+        if let Some(clint) = self.bus.device::<Clint>() {
+            if clint.software_pending(0) {
+                mip |= MIP_MSIP;
+            }
+            if clint.timer_pending(0) {
+                mip |= MIP_MTIP;
+            }
+        }
+        if self.sstc_enabled() {
+            mip &= !MIP_STIP;
+            if self.supervisor_timer_pending() {
+                mip |= MIP_STIP;
+            }
+        }
+        if let Some(plic) = self.bus.device::<Plic>() {
+            if plic.is_interrupting(Plic::m_context(0)) {
+                mip |= MIP_MEIP;
+            }
+            if plic.is_interrupting(Plic::s_context(0)) {
+                mip |= MIP_SEIP;
+            }
+        }
+        self.csregs[MIP] = mip;
+    }
+
+    // Returns the highest-priority interrupt that is pending, enabled in mie and
+    // not masked at the current privilege level, if any.
+    pub fn check_pending_interrupt(&mut self) -> Option<Interrupt> {
+        self.update_mip();
+        let pending = self.load_csr(MIP) & self.load_csr(MIE);
+        if pending == 0 {
+            return None;
+        }
+        let mideleg = self.load_csr(MIDELEG);
+        // interrupts for a more privileged mode are always enabled; for the
+        // current mode they need the global enable bit
+        let m_enabled = self.curr_mode < Mode::Machine || self.load_csr(MSTATUS) & MSTATUS_MIE != 0;
+        let s_enabled = self.curr_mode < Mode::Supervisor
+            || (self.curr_mode == Mode::Supervisor && self.load_csr(SSTATUS) & MSTATUS_SIE != 0);
+        let m_pending = if m_enabled { pending & !mideleg } else { 0 };
+        let s_pending = if s_enabled { pending & mideleg } else { 0 };
+        for pending in [m_pending, s_pending] {
+            if let Some(interrupt) = Interrupt::PRIORITY.iter().find(|i| pending & i.mask() != 0) {
+                return Some(*interrupt);
+            }
+        }
+        None
+    }
+
+    // Whether this hart implements `csr`.
+    fn csr_exists(csr: usize) -> bool {
+        match csr {
+            // RV64 has only the even-numbered pmpcfg registers
+            PMPCFG0..=PMPCFG15 => csr.is_multiple_of(2),
+            FFLAGS | FRM | FCSR
+            | CYCLE..=HPMCOUNTER31
+            | SSTATUS | SIE | STVEC | SCOUNTEREN | SSCRATCH | SEPC | SCAUSE | STVAL | SIP | SATP | STIMECMP
+            | MVENDORID | MARCHID | MIMPID | MHARTID
+            | MSTATUS | MISA | MEDELEG | MIDELEG | MIE | MTVEC | MCOUNTEREN | MENVCFG
+            | MCOUNTINHIBIT | MHPMEVENT3..=MHPMEVENT31
+            | MSCRATCH | MEPC | MCAUSE | MTVAL | MIP
+            | PMPADDR0..=PMPADDR63
+            | MCYCLE | MINSTRET | MHPMCOUNTER3..=MHPMCOUNTER31 => true,
+            _ => false,
+        }
+    }
+
+    // Checks a Zicsr instruction's access to `csr` against the current mode.
+    // CSRs the hart does not implement cannot be accessed at all. Bits 9:8
+    // of the address give the lowest mode that may access a CSR, and the
+    // CSRs with bits 11:10 set are read-only.
+    fn check_csr_access(&self, csr: usize, write: bool, instruction: u64) -> Result<(), Exception> {
+        if !Self::csr_exists(csr) || (self.curr_mode as usize) < (csr >> 8) & 0b11 || (write && csr >> 10 == 0b11) {
+            return Err(Exception::IllegalInstruction(instruction));
+        }
+        match csr {
+            FFLAGS | FRM | FCSR => self.check_fs(instruction),
+            SATP => self.check_vm_access(instruction),
+            CYCLE..=HPMCOUNTER31 => self.check_counter_access(csr, instruction),
+            STIMECMP => self.check_stimecmp_access(instruction),
+            _ => Ok(()),
+        }
     }
 
+    // Reads a CSR without any permission check. sstatus, sie and sip are
+    // views of their machine-mode counterparts.
     pub fn load_csr(&self, addr: usize) -> u64{
         match addr{
+            FFLAGS => self.csregs[FCSR] & 0x1f,
+            FRM => (self.csregs[FCSR] >> 5) & 0x7,
+            SSTATUS => self.csregs[MSTATUS] & SSTATUS_MASK,
             SIE => self.csregs[MIE] & self.csregs[MIDELEG],
+            SIP => self.csregs[MIP] & self.csregs[MIDELEG],
+            MISA => MISA_VALUE,
+            PMPCFG0..=PMPCFG15 => self.pmp.read_cfg(addr - PMPCFG0),
+            PMPADDR0..=PMPADDR63 => self.pmp.read_addr(addr - PMPADDR0),
+            CYCLE..=HPMCOUNTER31 => self.load_counter(addr),
+            MHPMCOUNTER3..=MHPMCOUNTER31 | MHPMEVENT3..=MHPMEVENT31 => 0,
             _ => self.csregs[addr],
         }
     }
 
+    // Writes a CSR without any permission check. Only the writable bits of
+    // each register change; WARL fields ignore values they cannot hold.
     pub fn store_csr(&mut self, addr: usize, value: u64){
         match addr{
+            MSTATUS => self.write_mstatus(value, MSTATUS_WRITABLE),
+            SSTATUS => self.write_mstatus(value, SSTATUS_MASK & MSTATUS_WRITABLE),
+            MISA => {}
+            PMPCFG0..=PMPCFG15 => self.pmp.write_cfg(addr - PMPCFG0, value),
+            PMPADDR0..=PMPADDR63 => self.pmp.write_addr(addr - PMPADDR0, value),
+            MCOUNTEREN | SCOUNTEREN => self.csregs[addr] = value & 0xffff_ffff,
+            // bit 1 would inhibit time, which is not a counter of this hart
+            MCOUNTINHIBIT => self.csregs[addr] = value & 0xffff_fffd,
+            // the event counters count nothing
+            MHPMCOUNTER3..=MHPMCOUNTER31 | MHPMEVENT3..=MHPMEVENT31 => {}
+            MEDELEG => self.csregs[MEDELEG] = value & MEDELEG_WRITABLE,
+            MIDELEG => self.csregs[MIDELEG] = value & MIDELEG_WRITABLE,
+            MIE => self.csregs[MIE] = value & MIE_WRITABLE,
+            MIP => {
+                // Sstc drives STIP itself once enabled
+                let mask = if self.sstc_enabled() { MIP_WRITABLE & !MIP_STIP } else { MIP_WRITABLE };
+                self.csregs[MIP] = (self.csregs[MIP] & !mask) | (value & mask);
+            }
+            MENVCFG => self.csregs[MENVCFG] = value & MENVCFG_STCE,
+            // only SSIP can be written through sip, and only once delegated
+            SIP => {
+                let mask = MIP_SSIP & self.csregs[MIDELEG];
+                self.csregs[MIP] = (self.csregs[MIP] & !mask) | (value & mask);
+            }
+            // vectored (1) and direct (0) are the only modes
+            MTVEC | STVEC => self.csregs[addr] = value & !0b10,
+            // with compressed instructions epc only needs 2-byte alignment
+            MEPC | SEPC => self.csregs[addr] = value & !1,
+            // a write selecting an unsupported translation mode has no effect
+            SATP => {
+                if matches!(value >> 60, 0 | SATP_MODE_SV39 | SATP_MODE_SV48) {
+                    self.csregs[SATP] = value;
+                }
+            }
+            FFLAGS => {
+                self.csregs[FCSR] = (self.csregs[FCSR] & !0x1f) | (value & 0x1f);
+                self.mark_fs_dirty();
+            }
+            FRM => {
+                self.csregs[FCSR] = (self.csregs[FCSR] & !0xe0) | ((value & 0x7) << 5);
+                self.mark_fs_dirty();
+            }
+            FCSR => {
+                self.csregs[FCSR] = value & 0xff;
+                self.mark_fs_dirty();
+            }
             SIE => {
                 let mask = self.csregs[MIDELEG];
-                self.csregs[MIE] = (self.csregs[MIE] & !mask) | (value & mask);
+                self.csregs[MIE] = (self.csregs[MIE] & !mask) | (value & mask & MIE_WRITABLE);
             }
             _ => self.csregs[addr] = value,
         }
+        if self.trace.is_some() {
+            let written = self.load_csr(addr);
+            if let Some(trace) = self.trace.as_mut() {
+                trace.csr_write(addr, written);
+            }
+        }
+    }
+
+    // Writes the bits of mstatus in `mask`. MPP keeps its old value if the
+    // new one is the reserved encoding, and SD summarizes FS.
+    fn write_mstatus(&mut self, value: u64, mask: u64) {
+        let old = self.csregs[MSTATUS];
+        let mut mstatus = (old & !mask) | (value & mask);
+        if mstatus & MSTATUS_MPP == 2 << 11 {
+            mstatus = (mstatus & !MSTATUS_MPP) | (old & MSTATUS_MPP);
+        }
+        if mstatus & MSTATUS_FS == MSTATUS_FS {
+            mstatus |= MSTATUS_SD;
+        } else {
+            mstatus &= !MSTATUS_SD;
+        }
+        self.csregs[MSTATUS] = mstatus;
+    }
+
+    fn mark_fs_dirty(&mut self) {
+        self.csregs[MSTATUS] |= MSTATUS_FS | MSTATUS_SD;
+    }
+
+    // FP instructions are illegal while mstatus.FS is Off.
+    fn check_fs(&self, instruction: u64) -> Result<(), Exception> {
+        if self.csregs[MSTATUS] & MSTATUS_FS == 0 {
+            return Err(Exception::IllegalInstruction(instruction));
+        }
+        Ok(())
+    }
+
+    fn write_freg(&mut self, reg: usize, value: u64) {
+        self.fregs[reg] = value;
+        self.mark_fs_dirty();
+        if let Some(trace) = self.trace.as_mut() {
+            trace.freg_write(reg, value);
+        }
+    }
+
+    fn accrue_fflags(&mut self, flags: u64) {
+        if flags != 0 {
+            self.csregs[FCSR] |= flags;
+            self.mark_fs_dirty();
+            if let Some(trace) = self.trace.as_mut() {
+                trace.csr_write(FFLAGS, self.csregs[FCSR] & 0x1f);
+            }
+        }
+    }
+
+    // Resolves an instruction's rm field, using frm for the dynamic mode.
+    fn rounding_mode(&self, rm: usize, instruction: u64) -> Result<u64, Exception> {
+        let rm = match rm as u64 {
+            RM_DYN => self.load_csr(FRM),
+            rm => rm,
+        };
+        if rm > RM_RMM {
+            return Err(Exception::IllegalInstruction(instruction));
+        }
+        Ok(rm)
+    }
+
+    fn fp_format(fmt: u64, instruction: u64) -> Result<Format, Exception> {
+        match fmt {
+            0b00 => Ok(F32),
+            0b01 => Ok(F64),
+            _ => Err(Exception::IllegalInstruction(instruction)),
+        }
+    }
+
+    // Runs one instruction, taking any pending interrupt first. On an
+    // exception pc is left at the faulting instruction and the caller decides
+    // whether to take the trap.
+    pub fn step(&mut self) -> Result<(), Exception> {
+        self.bus.tick();
+        if let Some(interrupt) = self.check_pending_interrupt() {
+            let pc = self.pc;
+            interrupt.handle_trap(self, pc);
+        }
+        if self.waiting {
+            // any interrupt enabled in mie wakes the hart, even one masked by
+            // the global enable bits
+            if self.csregs[MIP] & self.csregs[MIE] == 0 {
+                self.idle();
+                return Ok(());
+            }
+            self.waiting = false;
+        }
+
+        let pc = self.pc;
+        let instruction = self.fetch()?;
+        self.pc = self.pc.wrapping_add(if is_compressed(instruction) { 2 } else { 4 });
+        if let Some(trace) = self.trace.as_mut() {
+            trace.begin(pc, instruction, self.curr_mode);
+        }
+        let counters = (self.csregs[MCYCLE], self.csregs[MINSTRET]);
+        let result = self.execute(instruction);
+        self.advance_counters(counters, result.is_ok());
+        if let Some(trace) = self.trace.as_mut() {
+            trace.end(&self.registers, result.is_ok());
+        }
+        if result.is_err() {
+            self.pc = pc;
+        }
+        result
     }
 
-    pub fn execute(&mut self, instruction: u64) -> Result<(), Exception>{
-        let opcode = instruction & 0x7f;
-        let rd = ((instruction >> 7) & 0x1f) as usize;
-        let funct3 = ((instruction >> 12) & 0x07) as usize;
-        let funct7 = (instruction >> 25) & 0x7f;
-        let rs1 = ((instruction >> 15) & 0x1f) as usize;
-        let rs2 = ((instruction >> 20) & 0x1f) as usize;
+    // Lets time pass while the hart waits for an interrupt. If a timer
+    // interrupt is enabled, the CLINT skips ahead to the earliest deadline or
+    // says how far off it is. Other interrupts come from the host, so the
+    // thread sleeps in short naps to notice them.
+    fn idle(&mut self) {
+        let mut nap = Duration::from_millis(1);
+        let mie = self.csregs[MIE];
+        let stimecmp = (mie & MIP_STIP != 0 && self.sstc_enabled()).then_some(self.csregs[STIMECMP]);
+        if let Some(clint) = self.bus.device_mut::<Clint>() {
+            let mtimecmp = if mie & MIP_MTIP != 0 { clint.mtimecmp(0) } else { None };
+            let deadline = mtimecmp.into_iter().chain(stimecmp).min();
+            if let Some(wait) = deadline.and_then(|deadline| clint.skip_to(deadline)) {
+                nap = nap.min(wait);
+            }
+        }
+        if !nap.is_zero() {
+            thread::sleep(nap);
+        }
+    }
+
+    // Executes one instruction. The caller has already advanced pc past it, so
+    // pc-relative instructions subtract `inst_len` to find their own address.
+    // Illegal instruction exceptions carry `raw`, the bits as fetched, rather
+    // than the expansion of a compressed instruction.
+    pub fn execute(&mut self, raw: u64) -> Result<(), Exception>{
+        let (instruction, inst_len) = if is_compressed(raw) {
+            (expand(raw)?, 2)
+        } else {
+            (raw, 4)
+        };
+        let opcode = decode::opcode(instruction);
+        let rd = decode::rd(instruction);
+        let funct3 = decode::funct3(instruction);
+        let funct7 = decode::funct7(instruction);
+        let rs1 = decode::rs1(instruction);
+        let rs2 = decode::rs2(instruction);
 
         match opcode{
             0x03 =>{
-                let imm = ((instruction as i32 as i64) >> 20) as u64;
+                let imm = decode::imm_i(instruction);
                 let addr = self.registers[rs1].wrapping_add(imm);
                 match funct3{
                     //lb
@@ -137,13 +607,37 @@ impl Cpu{
                         self.registers[rd] = data;
                     }
                     _ => {
-                        eprintln!("Have not implemented opcode: {:#x} funct3: {:#x}", opcode, funct3);
-                        return Err(Exception::IllegalInstruction)
+                        return Err(Exception::IllegalInstruction(raw));
                     } 
                 }
             }
+            //flw, fld
+            0x07 => {
+                self.check_fs(raw)?;
+                let imm = decode::imm_i(instruction);
+                let addr = self.registers[rs1].wrapping_add(imm);
+                match funct3 {
+                    0x2 => {
+                        let data = self.load(addr, 32)?;
+                        self.write_freg(rd, nan_box(F32, data));
+                    }
+                    0x3 => {
+                        let data = self.load(addr, 64)?;
+                        self.write_freg(rd, data);
+                    }
+                    _ => {
+                        return Err(Exception::IllegalInstruction(raw));
+                    }
+                }
+            }
+            //fence, fence.i: accesses complete in order and there is no
+            //instruction cache, so both are no-ops
+            0x0f => match funct3 {
+                0x0 | 0x1 => {}
+                _ => return Err(Exception::IllegalInstruction(raw)),
+            },
             0x13 => {
-                let imm = ((instruction as i32 as i64) >> 20) as u64;
+                let imm = decode::imm_i(instruction);
                 let shiftamt = (imm & 0x3f) as u32;
                 match funct3 {
                     //addi
@@ -182,18 +676,17 @@ impl Cpu{
                     // andi
                     0x7 => self.registers[rd] = self.registers[rs1] & imm,
                     _ => {
-                        eprintln!("Have not implemented opcode: {:#x} funct3: {:#x}", opcode, funct3);
-                        return Err(Exception::IllegalInstruction)
+                        return Err(Exception::IllegalInstruction(raw));
                     }
                 }
             }
             //auipc
             0x17 => {
-                let imm = (instruction & 0xfffff000) as i32 as i64 as u64;
-                self.registers[rd] = self.pc.wrapping_add(imm).wrapping_sub(4);
+                let imm = decode::imm_u(instruction);
+                self.registers[rd] = self.pc.wrapping_add(imm).wrapping_sub(inst_len);
             }
             0x1b => {
-                let imm = ((instruction as i32 as i64) >> 20) as u64;
+                let imm = decode::imm_i(instruction);
                 let shiftamt = (imm & 0x1f) as u32;
                 match funct3 {
                     //addiw
@@ -215,20 +708,18 @@ impl Cpu{
                                 self.registers[rd] = (self.registers[rs1] as i32).wrapping_shr(shiftamt) as i64 as u64;
                             }
                             _ => {
-                                eprintln!("Have not implemented opcode: {:#x} funct3: {:#x}", opcode, funct3);
-                                return Err(Exception::IllegalInstruction)
+                                return Err(Exception::IllegalInstruction(raw));
                             }
                         }
                     }
                     _ => {
-                        eprintln!("Have not implemented opcode: {:#x} funct3: {:#x}", opcode, funct3);
-                        return Err(Exception::IllegalInstruction)
+                        return Err(Exception::IllegalInstruction(raw));
                     }
                 }
             }
             //sb, sh, sw, sd
             0x23 => {
-                let imm = (((instruction & 0xfe000000) as i32 as i64) >> 20) as u64 | ((instruction >> 7) & 0x1f);
+                let imm = decode::imm_s(instruction);
                 let addr = self.registers[rs1].wrapping_add(imm);
                 match funct3{
                     0x00 => {
@@ -244,8 +735,20 @@ impl Cpu{
                         self.store(addr, 64, self.registers[rs2])?;
                     }
                     _ => {
-                        eprintln!("Have not implemented opcode: {:#x} funct3: {:#x}", opcode, funct3);
-                        return Err(Exception::IllegalInstruction)
+                        return Err(Exception::IllegalInstruction(raw));
+                    }
+                }
+            }
+            //fsw, fsd
+            0x27 => {
+                self.check_fs(raw)?;
+                let imm = decode::imm_s(instruction);
+                let addr = self.registers[rs1].wrapping_add(imm);
+                match funct3 {
+                    0x2 => self.store(addr, 32, self.fregs[rs2])?,
+                    0x3 => self.store(addr, 64, self.fregs[rs2])?,
+                    _ => {
+                        return Err(Exception::IllegalInstruction(raw));
                     }
                 }
             }
@@ -254,35 +757,75 @@ impl Cpu{
                 let funct5 = (funct7 & 0b1111100) >> 2;
                 let _aq = (funct7 & 0b0000010) >> 1;
                 let _rl = funct7 & 0b0000001; 
-                match (funct3, funct5) {
-                    (0x2, 0x00) => {
-                        let val = self.load(self.registers[rs1], 32)?;
-                        self.registers[rd] = val;
-                        self.store(self.registers[rs1], 32, val.wrapping_add(self.registers[rs2]))?;
-                    }
-                    (0x3, 0x00) => {
-                        let val: u64 = self.load(self.registers[rs1], 64)?;
-                        self.registers[rd] = val;
-                        self.store(self.registers[rs1], 64, val.wrapping_add(self.registers[rs2]))?;
-                    }
-                    (0x2, 0x01) => {
-                        let val = self.load(self.registers[rs1], 32)?;
-                        self.registers[rd] = val;
-                        self.store(self.registers[rs1], 32, self.registers[rs2])?;
+                let addr = self.registers[rs1];
+                let size = match funct3 {
+                    0x2 => 32,
+                    0x3 => 64,
+                    _ => {
+                        return Err(Exception::IllegalInstruction(raw));
+                    }
+                };
+                if !addr.is_multiple_of(size / 8) {
+                    return match funct5 {
+                        0x02 => Err(Exception::LoadAddressMisaligned(addr)),
+                        _ => Err(Exception::StoreAMOAddressMisaligned(addr)),
+                    };
+                }
+                // sign-extends the loaded word for the .w variants
+                let extend = |val: u64| if size == 32 { val as i32 as i64 as u64 } else { val };
+                match funct5 {
+                    //lr
+                    0x02 => {
+                        let val = self.load(addr, size)?;
+                        self.reservation = Some(self.translate(addr, AccessType::Load)?);
+                        self.registers[rd] = extend(val);
                     }
-                    (0x3, 0x01) => {
-                        let val = self.load(self.registers[rs1], 64)?;
-                        self.registers[rd] = val;
-                        self.store(self.registers[rs1], 64, self.registers[rs2])?;
+                    //sc
+                    0x03 => {
+                        let paddr = self.translate(addr, AccessType::Store)?;
+                        if self.reservation == Some(paddr) {
+                            self.store(addr, size, self.registers[rs2])?;
+                            self.registers[rd] = 0;
+                        } else {
+                            self.registers[rd] = 1;
+                        }
+                        self.reservation = None;
                     }
                     _ => {
-                        eprintln!("Have not implemented funct3: {:#x} funct5: {:#x}", funct3, funct5);
-                        return Err(Exception::IllegalInstruction)
+                        // AMOs fault as stores even on their read half
+                        self.translate(addr, AccessType::Store)?;
+                        let val = extend(self.load(addr, size)?);
+                        let src = extend(self.registers[rs2]);
+                        let result = match funct5 {
+                            //amoadd
+                            0x00 => val.wrapping_add(src),
+                            //amoswap
+                            0x01 => src,
+                            //amoxor
+                            0x04 => val ^ src,
+                            //amoor
+                            0x08 => val | src,
+                            //amoand
+                            0x0c => val & src,
+                            //amomin
+                            0x10 => (val as i64).min(src as i64) as u64,
+                            //amomax
+                            0x14 => (val as i64).max(src as i64) as u64,
+                            //amominu
+                            0x18 => if size == 32 { (val as u32).min(src as u32) as u64 } else { val.min(src) },
+                            //amomaxu
+                            0x1c => if size == 32 { (val as u32).max(src as u32) as u64 } else { val.max(src) },
+                            _ => {
+                                return Err(Exception::IllegalInstruction(raw));
+                            }
+                        };
+                        self.store(addr, size, result)?;
+                        self.registers[rd] = val;
                     }
                 }
             }
             0x33 => {
-                let shiftamt = ((self.registers[rs2] & 0x3f) as u64) as u32;
+                let shiftamt = (self.registers[rs2] & 0x3f) as u32;
                 match (funct3, funct7) {
                     //add
                     (0x0, 0x00) => {
@@ -292,6 +835,48 @@ impl Cpu{
                     (0x0, 0x01) => {
                         self.registers[rd] = self.registers[rs1].wrapping_mul(self.registers[rs2]);
                     }
+                    //mulh
+                    (0x1, 0x01) => {
+                        let product = (self.registers[rs1] as i64 as i128) * (self.registers[rs2] as i64 as i128);
+                        self.registers[rd] = (product >> 64) as u64;
+                    }
+                    //mulhsu
+                    (0x2, 0x01) => {
+                        let product = (self.registers[rs1] as i64 as i128).wrapping_mul(self.registers[rs2] as i128);
+                        self.registers[rd] = (product >> 64) as u64;
+                    }
+                    //mulhu
+                    (0x3, 0x01) => {
+                        let product = (self.registers[rs1] as u128) * (self.registers[rs2] as u128);
+                        self.registers[rd] = (product >> 64) as u64;
+                    }
+                    //div
+                    (0x4, 0x01) => {
+                        let dividend = self.registers[rs1] as i64;
+                        let divisor = self.registers[rs2] as i64;
+                        self.registers[rd] = match divisor {
+                            0 => 0xffffffff_ffffffff,
+                            // overflow (i64::MIN / -1) wraps back to the dividend
+                            _ => dividend.wrapping_div(divisor) as u64,
+                        };
+                    }
+                    //rem
+                    (0x6, 0x01) => {
+                        let dividend = self.registers[rs1] as i64;
+                        let divisor = self.registers[rs2] as i64;
+                        self.registers[rd] = match divisor {
+                            0 => dividend as u64,
+                            // overflow (i64::MIN % -1) yields 0
+                            _ => dividend.wrapping_rem(divisor) as u64,
+                        };
+                    }
+                    //remu
+                    (0x7, 0x01) => {
+                        self.registers[rd] = match self.registers[rs2] {
+                            0 => self.registers[rs1],
+                            divisor => self.registers[rs1].wrapping_rem(divisor),
+                        };
+                    }
                     //sub
                     (0x0, 0x20) => {
                         self.registers[rd] = self.registers[rs1].wrapping_sub(self.registers[rs2]);
@@ -316,6 +901,7 @@ impl Cpu{
                     (0x5, 0x00) => {
                         self.registers[rd] = self.registers[rs1].wrapping_shr(shiftamt);
                     }
+                    //divu
                     (0x5, 0x01) => {
                         self.registers[rd] = match self.registers[rs2] {
                             0 => {
@@ -341,14 +927,13 @@ impl Cpu{
                         self.registers[rd] = if self.registers[rs1] < self.registers[rs2] { 1 } else { 0 };
                     }
                     _ => {
-                        eprintln!("Have not implemented opcode: {:#x} funct3: {:#x}", opcode, funct3);
-                        return Err(Exception::IllegalInstruction)
+                        return Err(Exception::IllegalInstruction(raw));
                     }
                 }
             }
             //lui
             0x37 => {
-                self.registers[rd] = (instruction & 0xfffff000) as i32 as i64 as u64;
+                self.registers[rd] = decode::imm_u(instruction);
             }
             0x3b => {
                 let shiftamt = (self.registers[rs2] & 0x1f) as u32;
@@ -374,66 +959,216 @@ impl Cpu{
                     (0x5, 0x20) => {
                         self.registers[rd] = (self.registers[rs1] as i32).wrapping_shr(shiftamt) as i64 as u64;
                     }
-                    // remuw
+                    //mulw
+                    (0x0, 0x01) => {
+                        self.registers[rd] = (self.registers[rs1] as i32).wrapping_mul(self.registers[rs2] as i32) as i64 as u64;
+                    }
+                    //divw
+                    (0x4, 0x01) => {
+                        let dividend = self.registers[rs1] as i32;
+                        let divisor = self.registers[rs2] as i32;
+                        self.registers[rd] = match divisor {
+                            0 => 0xffffffff_ffffffff,
+                            _ => dividend.wrapping_div(divisor) as i64 as u64,
+                        };
+                    }
+                    //divuw
+                    (0x5, 0x01) => {
+                        let dividend = self.registers[rs1] as u32;
+                        let divisor = self.registers[rs2] as u32;
+                        self.registers[rd] = match divisor {
+                            0 => 0xffffffff_ffffffff,
+                            _ => dividend.wrapping_div(divisor) as i32 as i64 as u64,
+                        };
+                    }
+                    //remw
+                    (0x6, 0x01) => {
+                        let dividend = self.registers[rs1] as i32;
+                        let divisor = self.registers[rs2] as i32;
+                        self.registers[rd] = match divisor {
+                            0 => dividend as i64 as u64,
+                            _ => dividend.wrapping_rem(divisor) as i64 as u64,
+                        };
+                    }
+                    //remuw
                     (0x7, 0x01) => {
-                        self.registers[rd] = match self.registers[rs2] {
-                            0 => self.registers[rs1],
-                            _ => {
-                                let dividend = self.registers[rs1] as u32;
-                                let divisor = self.registers[rs2] as u32;
-                                dividend.wrapping_rem(divisor) as u64
-                            }
+                        let dividend = self.registers[rs1] as u32;
+                        let divisor = self.registers[rs2] as u32;
+                        self.registers[rd] = match divisor {
+                            0 => dividend as i32 as i64 as u64,
+                            _ => dividend.wrapping_rem(divisor) as i32 as i64 as u64,
+                        };
+                    }
+                    _ => {
+                        return Err(Exception::IllegalInstruction(raw));
+                    }
+                }
+            }
+            //fmadd, fmsub, fnmsub, fnmadd
+            0x43 | 0x47 | 0x4b | 0x4f => {
+                self.check_fs(raw)?;
+                let fmt = Cpu::fp_format(funct7 & 0b11, raw)?;
+                let rm = self.rounding_mode(funct3, raw)?;
+                let rs3 = decode::rs3(instruction);
+                let (negate_product, negate_addend) = match opcode {
+                    0x43 => (false, false),
+                    0x47 => (false, true),
+                    0x4b => (true, false),
+                    _ => (true, true),
+                };
+                let mut flags = 0;
+                let result = fma(
+                    fmt,
+                    unbox(fmt, self.fregs[rs1]),
+                    unbox(fmt, self.fregs[rs2]),
+                    unbox(fmt, self.fregs[rs3]),
+                    negate_product,
+                    negate_addend,
+                    rm,
+                    &mut flags,
+                );
+                self.write_freg(rd, nan_box(fmt, result));
+                self.accrue_fflags(flags);
+            }
+            0x53 => {
+                self.check_fs(raw)?;
+                let fmt = Cpu::fp_format(funct7 & 0b11, raw)?;
+                let a = unbox(fmt, self.fregs[rs1]);
+                let b = unbox(fmt, self.fregs[rs2]);
+                let mut flags = 0;
+                match funct7 >> 2 {
+                    //fadd, fsub, fmul, fdiv
+                    0x00..=0x03 => {
+                        let rm = self.rounding_mode(funct3, raw)?;
+                        let result = match funct7 >> 2 {
+                            0x00 => add(fmt, a, b, rm, &mut flags),
+                            0x01 => sub(fmt, a, b, rm, &mut flags),
+                            0x02 => mul(fmt, a, b, rm, &mut flags),
+                            _ => div(fmt, a, b, rm, &mut flags),
+                        };
+                        self.write_freg(rd, nan_box(fmt, result));
+                    }
+                    //fsqrt
+                    0x0b if rs2 == 0 => {
+                        let rm = self.rounding_mode(funct3, raw)?;
+                        let result = sqrt(fmt, a, rm, &mut flags);
+                        self.write_freg(rd, nan_box(fmt, result));
+                    }
+                    //fsgnj, fsgnjn, fsgnjx
+                    0x04 => {
+                        let sign = fmt.sign_bit();
+                        let result = match funct3 {
+                            0x0 => (a & !sign) | (b & sign),
+                            0x1 => (a & !sign) | (!b & sign),
+                            0x2 => a ^ (b & sign),
+                            _ => return Err(Exception::IllegalInstruction(raw)),
                         };
+                        self.write_freg(rd, nan_box(fmt, result));
+                    }
+                    //fmin, fmax
+                    0x05 => {
+                        let result = match funct3 {
+                            0x0 => min_max(fmt, a, b, false, &mut flags),
+                            0x1 => min_max(fmt, a, b, true, &mut flags),
+                            _ => return Err(Exception::IllegalInstruction(raw)),
+                        };
+                        self.write_freg(rd, nan_box(fmt, result));
+                    }
+                    //fcvt.s.d, fcvt.d.s
+                    0x08 => {
+                        let rm = self.rounding_mode(funct3, raw)?;
+                        let from = Cpu::fp_format(rs2 as u64, raw)?;
+                        if from == fmt {
+                            return Err(Exception::IllegalInstruction(raw));
+                        }
+                        let result = convert(from, fmt, unbox(from, self.fregs[rs1]), rm, &mut flags);
+                        self.write_freg(rd, nan_box(fmt, result));
+                    }
+                    //fle, flt, feq
+                    0x14 => {
+                        let result = match funct3 {
+                            0x0 => compare(fmt, a, b, true, &mut flags).is_some_and(|ord| ord.is_le()),
+                            0x1 => compare(fmt, a, b, true, &mut flags).is_some_and(|ord| ord.is_lt()),
+                            0x2 => compare(fmt, a, b, false, &mut flags).is_some_and(|ord| ord.is_eq()),
+                            _ => return Err(Exception::IllegalInstruction(raw)),
+                        };
+                        self.registers[rd] = result as u64;
+                    }
+                    //fcvt.w, fcvt.wu, fcvt.l, fcvt.lu
+                    0x18 if rs2 < 4 => {
+                        let rm = self.rounding_mode(funct3, raw)?;
+                        let width = if rs2 < 2 { 32 } else { 64 };
+                        self.registers[rd] = to_int(fmt, a, rs2 & 1 == 0, width, rm, &mut flags);
+                    }
+                    //fcvt from w, wu, l, lu
+                    0x1a if rs2 < 4 => {
+                        let rm = self.rounding_mode(funct3, raw)?;
+                        let width = if rs2 < 2 { 32 } else { 64 };
+                        let result = from_int(fmt, self.registers[rs1], rs2 & 1 == 0, width, rm, &mut flags);
+                        self.write_freg(rd, nan_box(fmt, result));
+                    }
+                    0x1c if rs2 == 0 => match funct3 {
+                        //fmv.x.w, fmv.x.d
+                        0x0 => {
+                            self.registers[rd] = match fmt {
+                                F32 => self.fregs[rs1] as i32 as i64 as u64,
+                                _ => self.fregs[rs1],
+                            };
+                        }
+                        //fclass
+                        0x1 => self.registers[rd] = classify(fmt, a),
+                        _ => return Err(Exception::IllegalInstruction(raw)),
+                    },
+                    //fmv.w.x, fmv.d.x
+                    0x1e if rs2 == 0 && funct3 == 0 => {
+                        let value = match fmt {
+                            F32 => self.registers[rs1] & 0xffffffff,
+                            _ => self.registers[rs1],
+                        };
+                        self.write_freg(rd, nan_box(fmt, value));
                     }
                     _ => {
-                        println!(
-                            "not implemented yet: opcode {:#x} funct3 {:#x} funct7 {:#x}",
-                            opcode, funct3, funct7
-                        );
-                        return Err(Exception::IllegalInstruction);
+                        return Err(Exception::IllegalInstruction(raw));
                     }
                 }
+                self.accrue_fflags(flags);
             }
             //branchs
             0x63 => {
-                let imm = (((instruction & 0x80000000) as i32 as i64 >> 19) as u64)
-                    | ((instruction & 0x80) << 4) 
-                    | ((instruction >> 20) & 0x7e0) 
-                    | ((instruction >> 7) & 0x1e);
+                let imm = decode::imm_b(instruction);
                 match funct3 {
                     0x00 => {
                         if self.registers[rs1] == self.registers[rs2] {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(inst_len);
                         }
                     }
                     0x01 => {
                         if self.registers[rs1] != self.registers[rs2] {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(inst_len);
                         }
                     }
                     0x04 => {
                         if (self.registers[rs1] as i64) < (self.registers[rs2] as i64) {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(inst_len);
                         }
                     }
                     0x05 => {
                         if (self.registers[rs1] as i64) >= (self.registers[rs2] as i64) {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(inst_len);
                         }
                     }
                     0x06 => {
                         if self.registers[rs1] < self.registers[rs2] {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(inst_len);
                         }
                     }
                     0x07 => {
                         if self.registers[rs1] >= self.registers[rs2] {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(inst_len);
                         }
                     }
                     _ => {
-                        eprintln!("Have not implemented opcode: {:#x} funct3: {:#x}", opcode, funct3);
-                        return Err(Exception::IllegalInstruction)
+                        return Err(Exception::IllegalInstruction(raw));
                     }
                 }
             }
@@ -442,28 +1177,24 @@ impl Cpu{
                 match funct3 {
                     0x00 => {
                         let temp = self.pc;
-                        let imm = (((instruction >> 20) as i32) as i64) as u64;
+                        let imm = decode::imm_i(instruction);
                         self.pc = (self.registers[rs1].wrapping_add(imm)) & !1;
                         self.registers[rd] = temp;
                     }
                     _ => {
-                        eprintln!("Have not implemented opcode: {:#x} funct3: {:#x}", opcode, funct3);
-                        return Err(Exception::IllegalInstruction)
+                        return Err(Exception::IllegalInstruction(raw));
                     }
                 }
             }
             //jal 
             0x6f => {
-                let imm = (((instruction & 0x80000000) as i32 as i64 >> 11) as u64) 
-                    | (instruction & 0xff000) 
-                    | ((instruction >> 9) & 0x800) 
-                    | ((instruction >> 20) & 0x7fe);
+                let imm = decode::imm_j(instruction);
                 self.registers[rd] = self.pc;
-                self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                self.pc = self.pc.wrapping_add(imm).wrapping_sub(inst_len);
             }
             //zicsr
             0x73 => {
-                let csr = ((instruction >> 20) & 0xfff) as usize;
+                let csr = decode::csr(instruction);
                 match funct3{
                     0x0 => {
                         match (rs2, funct7) {
@@ -475,96 +1206,48 @@ impl Cpu{
                                 }
                             }
                             (0x1, 0x0) => {
-                                return Err(Exception::Breakpoint)
-                            }
-                            (0x2, 0x8) => {
-                                self.pc = self.load_csr(SEPC);
-                                let mode = self.load_csr(SSTATUS) >> 8 & 1;
-                                match mode {
-                                    1 => self.curr_mode = Mode::Supervisor,
-                                    _ => self.curr_mode = Mode::User,
-                                };
-                                let mut new_sstatus = self.load_csr(SSTATUS);
-                                let spie = (self.load_csr(SSTATUS) >> 5) & 1; 
-                                if spie == 1 {
-                                    new_sstatus |= 1 << 1; 
-                                } else {
-                                    new_sstatus &= !(1 << 1); 
-                                }
-                                new_sstatus |= 1 << 5;
-                                new_sstatus &= !(1 << 8); 
-                                self.store_csr(SSTATUS, new_sstatus);
+                                return Err(Exception::Breakpoint(self.pc.wrapping_sub(inst_len)))
                             }
-                            (0x2, 0x18) => {
-                                self.pc = self.load_csr(MEPC);
-                                let mode = (self.load_csr(MSTATUS) >> 11) & 0b11;
-                                match mode {
-                                    2 => self.curr_mode = Mode::Machine,
-                                    1 => self.curr_mode = Mode::Supervisor,
-                                    _ => self.curr_mode = Mode::User,
-                                }
-                                let mut new_mstatus = self.load_csr(MSTATUS);
-                                let spie = (self.load_csr(MSTATUS) >> 7) & 1; 
-                                if spie == 1 {
-                                    new_mstatus |= 1 << 3; 
-                                } else {
-                                    new_mstatus &= !(1 << 3); 
-                                }
-                                new_mstatus |= 1 << 7;
-                                new_mstatus &= !(0b11 << 11);
-                                self.store_csr(MSTATUS, new_mstatus);
+                            //sret
+                            (0x2, 0x8) => self.sret(raw)?,
+                            //sfence.vma: no TLB is cached, so there is nothing to flush
+                            (_, 0x9) => self.check_vm_access(raw)?,
+                            //mret
+                            (0x2, 0x18) => self.mret(raw)?,
+                            //wfi
+                            (0x5, 0x8) => {
+                                self.check_wfi(raw)?;
+                                self.waiting = true;
                             }
                             _ => {
-                                println!(
-                                    "not implemented yet: opcode {:#x} funct3 {:#x} funct7 {:#x}",
-                                    opcode, funct3, funct7
-                                );
-                                return Err(Exception::IllegalInstruction);
+                                return Err(Exception::IllegalInstruction(raw));
                             }
                         }
                     }
-                    0x1 => {
-                        let val = self.load_csr(csr);
-                        self.store_csr(csr, self.registers[rs1]);
-                        self.registers[rd] = val;
-                    }
-                    0x2 => {
-                        let val = self.load_csr(csr);
-                        self.store_csr(csr, val | self.registers[rs1]);
-                        self.registers[rd] = val;
-                    }
-                    0x3 => {
+                    //csrrw, csrrs, csrrc and their immediate forms
+                    0x1 | 0x2 | 0x3 | 0x5 | 0x6 | 0x7 => {
+                        let src = if funct3 & 0x4 != 0 { rs1 as u64 } else { self.registers[rs1] };
+                        // csrrs and csrrc with x0 or a zero immediate only read
+                        let write = funct3 & 0x3 == 0x1 || rs1 != 0;
+                        self.check_csr_access(csr, write, raw)?;
                         let val = self.load_csr(csr);
-                        self.store_csr(csr, val & (!self.registers[rs1]));
+                        if write {
+                            let new = match funct3 & 0x3 {
+                                0x1 => src,
+                                0x2 => val | src,
+                                _ => val & !src,
+                            };
+                            self.store_csr(csr, new);
+                        }
                         self.registers[rd] = val;
                     }
-                    0x5 => {
-                        let imm = rs1 as u64;
-                        self.registers[rd] = self.load_csr(csr);
-                        self.store_csr(csr, imm);
-                    }
-                    0x6 => {
-                        let imm = rs1 as u64;
-                        let t = self.load_csr(csr);
-                        self.store_csr(csr, t | imm);
-                        self.registers[rd] = t;
-                    }
-                    0x7 => {
-                        let imm = rs1 as u64;
-                        let t = self.load_csr(csr);
-                        self.store_csr(csr, t & (!imm));
-                        self.registers[rd] = t;
-                    }
                     _ => {
-                        eprintln!("Have not implemented opcode: {:#x} funct3: {:#x}", opcode, funct3);
-                        return Err(Exception::IllegalInstruction)
+                        return Err(Exception::IllegalInstruction(raw));
                     }
                 }
             }
             _ =>{
-                dbg!("Not done");
-                eprintln!("Have not implemented opcode: {:#x}", opcode);
-                return Err(Exception::IllegalInstruction)
+                return Err(Exception::IllegalInstruction(raw));
             }
         }
         self.registers[0] = 0;
@@ -580,23 +1263,20 @@ impl Cpu{
         ];
         for i in (0..32).step_by(4) {
             output = format!(
-                "{}\n{}",
+                "{}\nx{:02}({})={:>#18x} x{:02}({})={:>#18x} x{:02}({})={:>#18x} x{:02}({})={:>#18x}",
                 output,
-                format!(
-                    "x{:02}({})={:>#18x} x{:02}({})={:>#18x} x{:02}({})={:>#18x} x{:02}({})={:>#18x}",
-                    i,
-                    abi[i],
-                    self.registers[i],
-                    i + 1,
-                    abi[i + 1],
-                    self.registers[i + 1],
-                    i + 2,
-                    abi[i + 2],
-                    self.registers[i + 2],
-                    i + 3,
-                    abi[i + 3],
-                    self.registers[i + 3],
-                )
+                i,
+                abi[i],
+                self.registers[i],
+                i + 1,
+                abi[i + 1],
+                self.registers[i + 1],
+                i + 2,
+                abi[i + 2],
+                self.registers[i + 2],
+                i + 3,
+                abi[i + 3],
+                self.registers[i + 3],
             );
         }
         println!("{}", output);
@@ -604,23 +1284,16 @@ impl Cpu{
 
     pub fn dump_csrs(&self) {
         let output = format!(
-            "{}\n{}",
-            format!(
-                "mstatus={:>#18x} mtvec={:>#18x} mepc={:>#18x} mcause={:>#18x}",
-                self.load_csr(MSTATUS),
-                self.load_csr(MTVEC),
-                self.load_csr(MEPC),
-                self.load_csr(MCAUSE),
-            ),
-            format!(
-                "sstatus={:>#18x} stvec={:>#18x} sepc={:>#18x} scause={:>#18x}",
-                self.load_csr(SSTATUS),
-                self.load_csr(STVEC),
-                self.load_csr(SEPC),
-                self.load_csr(SCAUSE),
-            ),
+            "mstatus={:>#18x} mtvec={:>#18x} mepc={:>#18x} mcause={:>#18x}\nsstatus={:>#18x} stvec={:>#18x} sepc={:>#18x} scause={:>#18x}",
+            self.load_csr(MSTATUS),
+            self.load_csr(MTVEC),
+            self.load_csr(MEPC),
+            self.load_csr(MCAUSE),
+            self.load_csr(SSTATUS),
+            self.load_csr(STVEC),
+            self.load_csr(SEPC),
+            self.load_csr(SCAUSE),
         );
         println!("{}", output);
     }
 }
-
@@ -0,0 +1,60 @@
+// Field extraction for 32-bit instruction encodings, shared by the
+// interpreter and the disassembler. Immediates come back sign-extended.
+
+pub fn opcode(inst: u64) -> u64 {
+    inst & 0x7f
+}
+
+pub fn rd(inst: u64) -> usize {
+    ((inst >> 7) & 0x1f) as usize
+}
+
+pub fn rs1(inst: u64) -> usize {
+    ((inst >> 15) & 0x1f) as usize
+}
+
+pub fn rs2(inst: u64) -> usize {
+    ((inst >> 20) & 0x1f) as usize
+}
+
+pub fn rs3(inst: u64) -> usize {
+    ((inst >> 27) & 0x1f) as usize
+}
+
+pub fn funct3(inst: u64) -> usize {
+    ((inst >> 12) & 0x07) as usize
+}
+
+pub fn funct7(inst: u64) -> u64 {
+    (inst >> 25) & 0x7f
+}
+
+pub fn csr(inst: u64) -> usize {
+    ((inst >> 20) & 0xfff) as usize
+}
+
+pub fn imm_i(inst: u64) -> u64 {
+    ((inst as i32 as i64) >> 20) as u64
+}
+
+pub fn imm_s(inst: u64) -> u64 {
+    (((inst & 0xfe000000) as i32 as i64) >> 20) as u64 | ((inst >> 7) & 0x1f)
+}
+
+pub fn imm_b(inst: u64) -> u64 {
+    (((inst & 0x80000000) as i32 as i64 >> 19) as u64)
+        | ((inst & 0x80) << 4)
+        | ((inst >> 20) & 0x7e0)
+        | ((inst >> 7) & 0x1e)
+}
+
+pub fn imm_u(inst: u64) -> u64 {
+    (inst & 0xfffff000) as i32 as i64 as u64
+}
+
+pub fn imm_j(inst: u64) -> u64 {
+    (((inst & 0x80000000) as i32 as i64 >> 11) as u64)
+        | (inst & 0xff000)
+        | ((inst >> 9) & 0x800)
+        | ((inst >> 20) & 0x7fe)
+}
@@ -0,0 +1,472 @@
+// Renders instructions in the assembly syntax Spike's disassembler uses:
+// ABI register names, the common pseudo-instructions, and branch and jump
+// targets relative to pc. Compressed instructions are shown as the 32-bit
+// instruction they expand to.
+
+use crate::compressed::*;
+use crate::counters::*;
+use crate::cpu::*;
+use crate::decode::*;
+use crate::pmp::*;
+
+pub const XREG_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+pub const FREG_NAMES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2", "fa3", "fa4", "fa5",
+    "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9", "fs10", "fs11", "ft8", "ft9", "ft10",
+    "ft11",
+];
+
+pub fn csr_name(csr: usize) -> String {
+    let name = match csr {
+        FFLAGS => "fflags",
+        FRM => "frm",
+        FCSR => "fcsr",
+        CYCLE => "cycle",
+        TIME => "time",
+        INSTRET => "instret",
+        SSTATUS => "sstatus",
+        SIE => "sie",
+        STVEC => "stvec",
+        SCOUNTEREN => "scounteren",
+        SSCRATCH => "sscratch",
+        SEPC => "sepc",
+        SCAUSE => "scause",
+        STVAL => "stval",
+        SIP => "sip",
+        SATP => "satp",
+        STIMECMP => "stimecmp",
+        MVENDORID => "mvendorid",
+        MARCHID => "marchid",
+        MIMPID => "mimpid",
+        MHARTID => "mhartid",
+        MSTATUS => "mstatus",
+        MISA => "misa",
+        MEDELEG => "medeleg",
+        MIDELEG => "mideleg",
+        MIE => "mie",
+        MTVEC => "mtvec",
+        MCOUNTEREN => "mcounteren",
+        MENVCFG => "menvcfg",
+        MCOUNTINHIBIT => "mcountinhibit",
+        MSCRATCH => "mscratch",
+        MEPC => "mepc",
+        MCAUSE => "mcause",
+        MTVAL => "mtval",
+        MIP => "mip",
+        MCYCLE => "mcycle",
+        MINSTRET => "minstret",
+        PMPCFG0..=PMPCFG15 => return format!("pmpcfg{}", csr - PMPCFG0),
+        PMPADDR0..=PMPADDR63 => return format!("pmpaddr{}", csr - PMPADDR0),
+        _ => return format!("{:#x}", csr),
+    };
+    name.to_string()
+}
+
+// Formats a mnemonic and its operands, padding the mnemonic as Spike does.
+fn format_inst(name: &str, operands: &[String]) -> String {
+    if operands.is_empty() {
+        return name.to_string();
+    }
+    format!("{:<7} {}", name, operands.join(", "))
+}
+
+fn pc_relative(offset: u64) -> String {
+    let offset = offset as i64;
+    if offset < 0 {
+        format!("pc - {}", offset.unsigned_abs())
+    } else {
+        format!("pc + {}", offset)
+    }
+}
+
+fn x(reg: usize) -> String {
+    XREG_NAMES[reg].to_string()
+}
+
+fn f(reg: usize) -> String {
+    FREG_NAMES[reg].to_string()
+}
+
+fn signed(imm: u64) -> String {
+    (imm as i64).to_string()
+}
+
+fn address(imm: u64, base: usize) -> String {
+    format!("{}({})", imm as i64, XREG_NAMES[base])
+}
+
+fn fp_suffix(fmt: u64) -> Option<&'static str> {
+    match fmt {
+        0 => Some("s"),
+        1 => Some("d"),
+        _ => None,
+    }
+}
+
+// Appends the static rounding mode to an operand list; the dynamic mode is
+// left implicit.
+fn with_rm(mut operands: Vec<String>, rm: usize) -> Vec<String> {
+    let name = match rm {
+        0 => "rne",
+        1 => "rtz",
+        2 => "rdn",
+        3 => "rup",
+        4 => "rmm",
+        _ => return operands,
+    };
+    operands.push(name.to_string());
+    operands
+}
+
+// Disassembles one instruction, either a compressed 16-bit parcel or a full
+// 32-bit encoding. Anything that is not a valid RV64GC instruction comes back
+// as "unknown".
+pub fn disassemble(instruction: u64) -> String {
+    let inst = if is_compressed(instruction) {
+        match expand(instruction) {
+            Ok(inst) => inst,
+            Err(_) => return "unknown".to_string(),
+        }
+    } else {
+        instruction
+    };
+    decode_inst(inst).unwrap_or_else(|| "unknown".to_string())
+}
+
+fn decode_inst(inst: u64) -> Option<String> {
+    let opcode = opcode(inst);
+    let rd = rd(inst);
+    let rs1 = rs1(inst);
+    let rs2 = rs2(inst);
+    let funct3 = funct3(inst);
+    let funct7 = funct7(inst);
+
+    let text = match opcode {
+        0x03 => {
+            let name = ["lb", "lh", "lw", "ld", "lbu", "lhu", "lwu"].get(funct3)?;
+            format_inst(name, &[x(rd), address(imm_i(inst), rs1)])
+        }
+        0x07 => {
+            let name = match funct3 {
+                0x2 => "flw",
+                0x3 => "fld",
+                _ => return None,
+            };
+            format_inst(name, &[f(rd), address(imm_i(inst), rs1)])
+        }
+        0x0f => match funct3 {
+            0x0 => "fence".to_string(),
+            0x1 => "fence.i".to_string(),
+            _ => return None,
+        },
+        0x13 => {
+            let imm = imm_i(inst);
+            let shamt = (imm & 0x3f).to_string();
+            match funct3 {
+                0x0 if rd == 0 && rs1 == 0 && imm == 0 => "nop".to_string(),
+                0x0 if rs1 == 0 => format_inst("li", &[x(rd), signed(imm)]),
+                0x0 if imm == 0 => format_inst("mv", &[x(rd), x(rs1)]),
+                0x0 => format_inst("addi", &[x(rd), x(rs1), signed(imm)]),
+                0x1 if imm >> 6 == 0 => format_inst("slli", &[x(rd), x(rs1), shamt]),
+                0x2 => format_inst("slti", &[x(rd), x(rs1), signed(imm)]),
+                0x3 if imm == 1 => format_inst("seqz", &[x(rd), x(rs1)]),
+                0x3 => format_inst("sltiu", &[x(rd), x(rs1), signed(imm)]),
+                0x4 if imm as i64 == -1 => format_inst("not", &[x(rd), x(rs1)]),
+                0x4 => format_inst("xori", &[x(rd), x(rs1), signed(imm)]),
+                0x5 if (imm >> 6) & 0x3f == 0x00 => format_inst("srli", &[x(rd), x(rs1), shamt]),
+                0x5 if (imm >> 6) & 0x3f == 0x10 => format_inst("srai", &[x(rd), x(rs1), shamt]),
+                0x6 => format_inst("ori", &[x(rd), x(rs1), signed(imm)]),
+                0x7 => format_inst("andi", &[x(rd), x(rs1), signed(imm)]),
+                _ => return None,
+            }
+        }
+        0x17 => format_inst("auipc", &[x(rd), format!("{:#x}", (imm_u(inst) >> 12) & 0xfffff)]),
+        0x1b => {
+            let imm = imm_i(inst);
+            let shamt = (imm & 0x1f).to_string();
+            match (funct3, funct7) {
+                (0x0, _) if imm == 0 => format_inst("sext.w", &[x(rd), x(rs1)]),
+                (0x0, _) => format_inst("addiw", &[x(rd), x(rs1), signed(imm)]),
+                (0x1, 0x00) => format_inst("slliw", &[x(rd), x(rs1), shamt]),
+                (0x5, 0x00) => format_inst("srliw", &[x(rd), x(rs1), shamt]),
+                (0x5, 0x20) => format_inst("sraiw", &[x(rd), x(rs1), shamt]),
+                _ => return None,
+            }
+        }
+        0x23 => {
+            let name = ["sb", "sh", "sw", "sd"].get(funct3)?;
+            format_inst(name, &[x(rs2), address(imm_s(inst), rs1)])
+        }
+        0x27 => {
+            let name = match funct3 {
+                0x2 => "fsw",
+                0x3 => "fsd",
+                _ => return None,
+            };
+            format_inst(name, &[f(rs2), address(imm_s(inst), rs1)])
+        }
+        0x2f => {
+            let width = match funct3 {
+                0x2 => "w",
+                0x3 => "d",
+                _ => return None,
+            };
+            let op = match funct7 >> 2 {
+                0x00 => "amoadd",
+                0x01 => "amoswap",
+                0x02 => "lr",
+                0x03 => "sc",
+                0x04 => "amoxor",
+                0x08 => "amoor",
+                0x0c => "amoand",
+                0x10 => "amomin",
+                0x14 => "amomax",
+                0x18 => "amominu",
+                0x1c => "amomaxu",
+                _ => return None,
+            };
+            let ordering = match funct7 & 0b11 {
+                0b11 => ".aqrl",
+                0b10 => ".aq",
+                0b01 => ".rl",
+                _ => "",
+            };
+            let name = format!("{}.{}{}", op, width, ordering);
+            let base = format!("({})", XREG_NAMES[rs1]);
+            match op {
+                "lr" if rs2 == 0 => format_inst(&name, &[x(rd), base]),
+                "lr" => return None,
+                _ => format_inst(&name, &[x(rd), x(rs2), base]),
+            }
+        }
+        0x33 => {
+            let name = match (funct3, funct7) {
+                (0x0, 0x20) if rs1 == 0 => return Some(format_inst("neg", &[x(rd), x(rs2)])),
+                (0x3, 0x00) if rs1 == 0 => return Some(format_inst("snez", &[x(rd), x(rs2)])),
+                (0x0, 0x00) if rs1 == 0 => return Some(format_inst("mv", &[x(rd), x(rs2)])),
+                (0x0, 0x00) => "add",
+                (0x0, 0x20) => "sub",
+                (0x1, 0x00) => "sll",
+                (0x2, 0x00) => "slt",
+                (0x3, 0x00) => "sltu",
+                (0x4, 0x00) => "xor",
+                (0x5, 0x00) => "srl",
+                (0x5, 0x20) => "sra",
+                (0x6, 0x00) => "or",
+                (0x7, 0x00) => "and",
+                (_, 0x01) => ["mul", "mulh", "mulhsu", "mulhu", "div", "divu", "rem", "remu"][funct3],
+                _ => return None,
+            };
+            format_inst(name, &[x(rd), x(rs1), x(rs2)])
+        }
+        0x37 => format_inst("lui", &[x(rd), format!("{:#x}", (imm_u(inst) >> 12) & 0xfffff)]),
+        0x3b => {
+            let name = match (funct3, funct7) {
+                (0x0, 0x20) if rs1 == 0 => return Some(format_inst("negw", &[x(rd), x(rs2)])),
+                (0x0, 0x00) => "addw",
+                (0x0, 0x20) => "subw",
+                (0x1, 0x00) => "sllw",
+                (0x5, 0x00) => "srlw",
+                (0x5, 0x20) => "sraw",
+                (0x0, 0x01) => "mulw",
+                (0x4, 0x01) => "divw",
+                (0x5, 0x01) => "divuw",
+                (0x6, 0x01) => "remw",
+                (0x7, 0x01) => "remuw",
+                _ => return None,
+            };
+            format_inst(name, &[x(rd), x(rs1), x(rs2)])
+        }
+        0x43 | 0x47 | 0x4b | 0x4f => {
+            let suffix = fp_suffix(funct7 & 0b11)?;
+            let op = match opcode {
+                0x43 => "fmadd",
+                0x47 => "fmsub",
+                0x4b => "fnmsub",
+                _ => "fnmadd",
+            };
+            let operands = with_rm(vec![f(rd), f(rs1), f(rs2), f(rs3(inst))], funct3);
+            format_inst(&format!("{}.{}", op, suffix), &operands)
+        }
+        0x53 => return decode_fp(inst),
+        0x63 => {
+            let target = pc_relative(imm_b(inst));
+            match funct3 {
+                0x0 if rs2 == 0 => format_inst("beqz", &[x(rs1), target]),
+                0x1 if rs2 == 0 => format_inst("bnez", &[x(rs1), target]),
+                0x0 | 0x1 | 0x4..=0x7 => {
+                    let name = ["beq", "bne", "", "", "blt", "bge", "bltu", "bgeu"][funct3];
+                    format_inst(name, &[x(rs1), x(rs2), target])
+                }
+                _ => return None,
+            }
+        }
+        0x67 if funct3 == 0 => {
+            let imm = imm_i(inst);
+            match (rd, imm) {
+                (0, 0) if rs1 == 1 => "ret".to_string(),
+                (0, 0) => format_inst("jr", &[x(rs1)]),
+                (1, 0) => format_inst("jalr", &[x(rs1)]),
+                _ => format_inst("jalr", &[x(rd), address(imm, rs1)]),
+            }
+        }
+        0x6f => {
+            let target = pc_relative(imm_j(inst));
+            match rd {
+                0 => format_inst("j", &[target]),
+                1 => format_inst("jal", &[target]),
+                _ => format_inst("jal", &[x(rd), target]),
+            }
+        }
+        0x73 => return decode_system(inst),
+        _ => return None,
+    };
+    Some(text)
+}
+
+fn decode_fp(inst: u64) -> Option<String> {
+    let rd = rd(inst);
+    let rs1 = rs1(inst);
+    let rs2 = rs2(inst);
+    let funct3 = funct3(inst);
+    let funct7 = funct7(inst);
+    let suffix = fp_suffix(funct7 & 0b11)?;
+    let int_suffix = |rs2: usize| ["w", "wu", "l", "lu"].get(rs2).copied();
+
+    let (name, operands) = match funct7 >> 2 {
+        0x00..=0x03 => {
+            let op = ["fadd", "fsub", "fmul", "fdiv"][(funct7 >> 2) as usize];
+            (format!("{}.{}", op, suffix), with_rm(vec![f(rd), f(rs1), f(rs2)], funct3))
+        }
+        0x0b if rs2 == 0 => (format!("fsqrt.{}", suffix), with_rm(vec![f(rd), f(rs1)], funct3)),
+        0x04 => {
+            let op = match funct3 {
+                0x0 if rs1 == rs2 => "fmv",
+                0x1 if rs1 == rs2 => "fneg",
+                0x2 if rs1 == rs2 => "fabs",
+                0x0 => "fsgnj",
+                0x1 => "fsgnjn",
+                0x2 => "fsgnjx",
+                _ => return None,
+            };
+            let operands = if rs1 == rs2 { vec![f(rd), f(rs1)] } else { vec![f(rd), f(rs1), f(rs2)] };
+            (format!("{}.{}", op, suffix), operands)
+        }
+        0x05 => {
+            let op = match funct3 {
+                0x0 => "fmin",
+                0x1 => "fmax",
+                _ => return None,
+            };
+            (format!("{}.{}", op, suffix), vec![f(rd), f(rs1), f(rs2)])
+        }
+        0x08 => {
+            let from = fp_suffix(rs2 as u64)?;
+            if from == suffix {
+                return None;
+            }
+            (format!("fcvt.{}.{}", suffix, from), with_rm(vec![f(rd), f(rs1)], funct3))
+        }
+        0x14 => {
+            let op = match funct3 {
+                0x0 => "fle",
+                0x1 => "flt",
+                0x2 => "feq",
+                _ => return None,
+            };
+            (format!("{}.{}", op, suffix), vec![x(rd), f(rs1), f(rs2)])
+        }
+        0x18 => (
+            format!("fcvt.{}.{}", int_suffix(rs2)?, suffix),
+            with_rm(vec![x(rd), f(rs1)], funct3),
+        ),
+        0x1a => (
+            format!("fcvt.{}.{}", suffix, int_suffix(rs2)?),
+            with_rm(vec![f(rd), x(rs1)], funct3),
+        ),
+        0x1c if rs2 == 0 => match funct3 {
+            0x0 => (format!("fmv.x.{}", if suffix == "s" { "w" } else { "d" }), vec![x(rd), f(rs1)]),
+            0x1 => (format!("fclass.{}", suffix), vec![x(rd), f(rs1)]),
+            _ => return None,
+        },
+        0x1e if rs2 == 0 && funct3 == 0 => {
+            (format!("fmv.{}.x", if suffix == "s" { "w" } else { "d" }), vec![f(rd), x(rs1)])
+        }
+        _ => return None,
+    };
+    Some(format_inst(&name, &operands))
+}
+
+fn decode_system(inst: u64) -> Option<String> {
+    let rd = rd(inst);
+    let rs1 = rs1(inst);
+    let funct3 = funct3(inst);
+    let csr = csr(inst);
+
+    if funct3 == 0 {
+        if funct7(inst) == 0x09 && rd == 0 {
+            return Some(match (rs1, rs2(inst)) {
+                (0, 0) => "sfence.vma".to_string(),
+                (_, 0) => format_inst("sfence.vma", &[x(rs1)]),
+                (_, rs2) => format_inst("sfence.vma", &[x(rs1), x(rs2)]),
+            });
+        }
+        if rd != 0 || rs1 != 0 {
+            return None;
+        }
+        return match csr {
+            0x000 => Some("ecall".to_string()),
+            0x001 => Some("ebreak".to_string()),
+            0x102 => Some("sret".to_string()),
+            0x302 => Some("mret".to_string()),
+            0x105 => Some("wfi".to_string()),
+            _ => None,
+        };
+    }
+
+    let name = csr_name(csr);
+    let uimm = rs1.to_string();
+    let text = match funct3 {
+        0x1 if rd == 0 => format_inst("csrw", &[name, x(rs1)]),
+        0x2 if rs1 == 0 => format_inst("csrr", &[x(rd), name]),
+        0x2 if rd == 0 => format_inst("csrs", &[name, x(rs1)]),
+        0x3 if rd == 0 => format_inst("csrc", &[name, x(rs1)]),
+        0x5 if rd == 0 => format_inst("csrwi", &[name, uimm]),
+        0x6 if rd == 0 => format_inst("csrsi", &[name, uimm]),
+        0x7 if rd == 0 => format_inst("csrci", &[name, uimm]),
+        0x1..=0x3 => {
+            let op = ["", "csrrw", "csrrs", "csrrc"][funct3];
+            format_inst(op, &[x(rd), name, x(rs1)])
+        }
+        0x5..=0x7 => {
+            let op = ["", "", "", "", "", "csrrwi", "csrrsi", "csrrci"][funct3];
+            format_inst(op, &[x(rd), name, uimm])
+        }
+        _ => return None,
+    };
+    Some(text)
+}
+
+// Prints a listing of `code`, which starts at address `base`.
+pub fn dump(code: &[u8], base: u64) {
+    let mut offset = 0;
+    while offset + 2 <= code.len() {
+        let low = u16::from_le_bytes([code[offset], code[offset + 1]]) as u64;
+        let addr = base + offset as u64;
+        if is_compressed(low) {
+            println!("{:>16x}:  {:04x}      {}", addr, low, disassemble(low));
+            offset += 2;
+        } else if offset + 4 <= code.len() {
+            let high = u16::from_le_bytes([code[offset + 2], code[offset + 3]]) as u64;
+            let inst = low | (high << 16);
+            println!("{:>16x}:  {:08x}  {}", addr, inst, disassemble(inst));
+            offset += 4;
+        } else {
+            break;
+        }
+    }
+}
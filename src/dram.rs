@@ -1,5 +1,7 @@
 use crate::trap::*;
+use crate::bus::*;
 
+// the default amount of RAM
 pub const DRAM_SIZE: u64 = 1024 * 1024 * 128;
 pub const DRAM_BASE: u64 = 0x8000_0000;
 
@@ -8,31 +10,63 @@ pub struct Dram{
 }
 
 impl Dram{
-    pub fn new(code: Vec<u8>) -> Self{
-        let mut dram = vec![0; DRAM_SIZE as usize];
-        dram[..code.len()].copy_from_slice(&code);
-        Self { dram }
+    pub fn new(size: u64) -> Self{
+        Self { dram: vec![0; size as usize] }
     }
 
-    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception>{
+    pub fn size(&self) -> u64 {
+        self.dram.len() as u64
+    }
+
+    // Whether an access of `size` bits at `addr` lies entirely within RAM.
+    fn in_bounds(&self, addr: u64, size: u64) -> bool {
+        addr.checked_sub(DRAM_BASE)
+            .and_then(|index| index.checked_add(size / 8))
+            .is_some_and(|end| end <= self.size())
+    }
+}
+
+impl Device for Dram{
+    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception>{
+        if !self.in_bounds(addr, size) {
+            return Err(Exception::LoadAccessFault(addr));
+        }
         match size{
             8 => Ok(self.load8(addr)),
             16 => Ok(self.load16(addr)),
             32 => Ok(self.load32(addr)),
             64 => Ok(self.load64(addr)),
-            _ => Err(Exception::LoadAccessFault)
+            _ => Err(Exception::LoadAccessFault(addr))
         }
     }
 
-    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception>{
+    fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception>{
+        if !self.in_bounds(addr, size) {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
         match size{
-            8 => Ok(self.store8(addr, value)),
-            16 => Ok(self.store16(addr, value)),
-            32 => Ok(self.store32(addr, value)),
-            64 => Ok(self.store64(addr, value)),
-            _ => Err(Exception::StoreAMOAccessFault)
+            8 => {
+                self.store8(addr, value);
+                Ok(())
+            }
+            16 => {
+                self.store16(addr, value);
+                Ok(())
+            }
+            32 => {
+                self.store32(addr, value);
+                Ok(())
+            }
+            64 => {
+                self.store64(addr, value);
+                Ok(())
+            }
+            _ => Err(Exception::StoreAMOAccessFault(addr))
         }
     }
+}
+
+impl Dram{
 
     pub fn load8(&self, addr: u64) -> u64 {
         let index = (addr - DRAM_BASE) as usize;
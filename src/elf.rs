@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::bus::*;
+
+pub const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+pub const ELFCLASS64: u8 = 2;
+pub const ELFDATA2LSB: u8 = 1;
+pub const ET_EXEC: u16 = 2;
+pub const EM_RISCV: u16 = 243;
+pub const PT_LOAD: u32 = 1;
+pub const PF_X: u32 = 1;
+pub const SHT_SYMTAB: u32 = 2;
+
+const EHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+
+#[derive(Debug)]
+pub enum ElfError {
+    Truncated,
+    WrongClass(u8),
+    WrongEndianness(u8),
+    WrongType(u16),
+    WrongMachine(u16),
+    SegmentOutOfBounds { offset: u64, size: u64 },
+    SegmentUnmapped(u64),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::Truncated => write!(f, "ELF file is truncated"),
+            ElfError::WrongClass(class) => write!(f, "not a 64-bit ELF file (EI_CLASS = {})", class),
+            ElfError::WrongEndianness(data) => write!(f, "not a little-endian ELF file (EI_DATA = {})", data),
+            ElfError::WrongType(e_type) => write!(f, "not an executable ELF file (e_type = {})", e_type),
+            ElfError::WrongMachine(machine) => write!(f, "not a RISC-V ELF file (e_machine = {})", machine),
+            ElfError::SegmentOutOfBounds { offset, size } => {
+                write!(f, "segment at file offset {:#x} with size {:#x} runs past the end of the file", offset, size)
+            }
+            ElfError::SegmentUnmapped(addr) => write!(f, "segment address {:#x} is not backed by memory", addr),
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+// A PT_LOAD segment: `data` is placed at `paddr` and the remaining
+// `mem_size - data.len()` bytes are zero-filled.
+pub struct Segment {
+    pub paddr: u64,
+    pub data: Vec<u8>,
+    pub mem_size: u64,
+    pub executable: bool,
+}
+
+pub struct Elf {
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+    // named symbols from the symbol table, if the file has one
+    pub symbols: HashMap<String, u64>,
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let field = bytes.get(offset..offset.checked_add(2).ok_or(ElfError::Truncated)?).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes(field.try_into().unwrap()))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let field = bytes.get(offset..offset.checked_add(4).ok_or(ElfError::Truncated)?).ok_or(ElfError::Truncated)?;
+    Ok(u32::from_le_bytes(field.try_into().unwrap()))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64, ElfError> {
+    let field = bytes.get(offset..offset.checked_add(8).ok_or(ElfError::Truncated)?).ok_or(ElfError::Truncated)?;
+    Ok(u64::from_le_bytes(field.try_into().unwrap()))
+}
+
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(&ELF_MAGIC)
+}
+
+impl Elf {
+    pub fn parse(bytes: &[u8]) -> Result<Elf, ElfError> {
+        if bytes.len() < EHDR_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if bytes[4] != ELFCLASS64 {
+            return Err(ElfError::WrongClass(bytes[4]));
+        }
+        if bytes[5] != ELFDATA2LSB {
+            return Err(ElfError::WrongEndianness(bytes[5]));
+        }
+        let e_type = read_u16(bytes, 16)?;
+        if e_type != ET_EXEC {
+            return Err(ElfError::WrongType(e_type));
+        }
+        let machine = read_u16(bytes, 18)?;
+        if machine != EM_RISCV {
+            return Err(ElfError::WrongMachine(machine));
+        }
+        let entry = read_u64(bytes, 24)?;
+        let phoff = read_u64(bytes, 32)? as usize;
+        let phentsize = read_u16(bytes, 54)? as usize;
+        let phnum = read_u16(bytes, 56)? as usize;
+
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let ph = i.checked_mul(phentsize).and_then(|ph| ph.checked_add(phoff)).ok_or(ElfError::Truncated)?;
+            if read_u32(bytes, ph)? != PT_LOAD {
+                continue;
+            }
+            let flags = read_u32(bytes, ph + 4)?;
+            let offset = read_u64(bytes, ph + 8)?;
+            let paddr = read_u64(bytes, ph + 24)?;
+            let file_size = read_u64(bytes, ph + 32)?;
+            let mem_size = read_u64(bytes, ph + 40)?;
+            let data = offset
+                .checked_add(file_size)
+                .and_then(|end| bytes.get(offset as usize..end as usize))
+                .ok_or(ElfError::SegmentOutOfBounds { offset, size: file_size })?;
+            segments.push(Segment {
+                paddr,
+                data: data.to_vec(),
+                mem_size,
+                executable: flags & PF_X != 0,
+            });
+        }
+        let symbols = Elf::parse_symbols(bytes)?;
+        Ok(Elf { entry, segments, symbols })
+    }
+
+    fn parse_symbols(bytes: &[u8]) -> Result<HashMap<String, u64>, ElfError> {
+        let shoff = read_u64(bytes, 40)? as usize;
+        let shentsize = read_u16(bytes, 58)? as usize;
+        let shnum = read_u16(bytes, 60)? as usize;
+        // the start of section header i, which must lie within the file
+        let section = |i: usize| {
+            i.checked_mul(shentsize)
+                .and_then(|sh| sh.checked_add(shoff))
+                .filter(|sh| *sh < bytes.len())
+                .ok_or(ElfError::Truncated)
+        };
+
+        let mut symbols = HashMap::new();
+        for i in 0..shnum {
+            let sh = section(i)?;
+            if read_u32(bytes, sh + 4)? != SHT_SYMTAB {
+                continue;
+            }
+            let offset = read_u64(bytes, sh + 24)? as usize;
+            let size = read_u64(bytes, sh + 32)? as usize;
+            // sh_link names the string table holding the symbol names
+            let strtab = section(read_u32(bytes, sh + 40)? as usize)?;
+            let strtab_offset = read_u64(bytes, strtab + 24)? as usize;
+            let strtab_size = read_u64(bytes, strtab + 32)? as usize;
+            let names = strtab_offset
+                .checked_add(strtab_size)
+                .and_then(|end| bytes.get(strtab_offset..end))
+                .ok_or(ElfError::Truncated)?;
+            let end = offset.checked_add(size).ok_or(ElfError::Truncated)?;
+            for sym in (offset..end).step_by(SYM_SIZE) {
+                let name = read_u32(bytes, sym)? as usize;
+                let value = read_u64(bytes, sym + 8)?;
+                let name = names.get(name..).ok_or(ElfError::Truncated)?;
+                let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+                if !name.is_empty() {
+                    symbols.insert(String::from_utf8_lossy(name).into_owned(), value);
+                }
+            }
+        }
+        Ok(symbols)
+    }
+
+    // Copies every segment to its physical address and zero-fills the rest of
+    // its memory image (.bss).
+    pub fn load(&self, bus: &mut Bus) -> Result<(), ElfError> {
+        for segment in &self.segments {
+            let fill = (segment.data.len() as u64..segment.mem_size).map(|_| 0);
+            for (i, byte) in segment.data.iter().copied().chain(fill).enumerate() {
+                let addr = segment.paddr.checked_add(i as u64).ok_or(ElfError::SegmentUnmapped(segment.paddr))?;
+                bus.store(addr, 8, byte as u64).map_err(|_| ElfError::SegmentUnmapped(addr))?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,654 @@
+// Software IEEE 754 arithmetic for the F and D extensions.
+//
+// The host FPU can't be told which rounding mode to use or asked which
+// exception flags an operation raised, so every operation here works on
+// integer significands and rounds exactly once in `round_pack`.
+
+use std::cmp::Ordering;
+
+//fflags bits
+pub const FFLAGS_NX: u64 = 1 << 0;
+pub const FFLAGS_UF: u64 = 1 << 1;
+pub const FFLAGS_OF: u64 = 1 << 2;
+pub const FFLAGS_DZ: u64 = 1 << 3;
+pub const FFLAGS_NV: u64 = 1 << 4;
+
+//rounding modes
+pub const RM_RNE: u64 = 0b000;
+pub const RM_RTZ: u64 = 0b001;
+pub const RM_RDN: u64 = 0b010;
+pub const RM_RUP: u64 = 0b011;
+pub const RM_RMM: u64 = 0b100;
+pub const RM_DYN: u64 = 0b111;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Format {
+    pub exp_bits: u32,
+    pub frac_bits: u32,
+}
+
+pub const F32: Format = Format { exp_bits: 8, frac_bits: 23 };
+pub const F64: Format = Format { exp_bits: 11, frac_bits: 52 };
+
+impl Format {
+    fn bias(self) -> i32 {
+        (1 << (self.exp_bits - 1)) - 1
+    }
+
+    fn emin(self) -> i32 {
+        1 - self.bias()
+    }
+
+    fn exp_mask(self) -> u64 {
+        (1 << self.exp_bits) - 1
+    }
+
+    fn frac_mask(self) -> u64 {
+        (1 << self.frac_bits) - 1
+    }
+
+    pub fn sign_bit(self) -> u64 {
+        1 << (self.exp_bits + self.frac_bits)
+    }
+
+    pub fn canonical_nan(self) -> u64 {
+        (self.exp_mask() << self.frac_bits) | (1 << (self.frac_bits - 1))
+    }
+
+    fn zero(self, sign: bool) -> u64 {
+        if sign { self.sign_bit() } else { 0 }
+    }
+
+    fn infinity(self, sign: bool) -> u64 {
+        self.zero(sign) | (self.exp_mask() << self.frac_bits)
+    }
+
+    fn max_finite(self, sign: bool) -> u64 {
+        self.infinity(sign) - 1
+    }
+}
+
+// NaN-boxes a single-precision value for the 64-bit f registers.
+pub fn nan_box(fmt: Format, bits: u64) -> u64 {
+    if fmt == F32 { bits | 0xffffffff_00000000 } else { bits }
+}
+
+// Reads a register as `fmt`; improperly boxed singles read as the canonical NaN.
+pub fn unbox(fmt: Format, reg: u64) -> u64 {
+    if fmt != F32 {
+        reg
+    } else if reg >> 32 == 0xffffffff {
+        reg & 0xffffffff
+    } else {
+        F32.canonical_nan()
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Value {
+    Nan { signaling: bool },
+    Inf(bool),
+    Zero(bool),
+    // sign, significand with its msb at bit `frac_bits`, exponent of the significand's lsb
+    Finite(bool, u128, i32),
+}
+
+fn unpack(fmt: Format, bits: u64) -> Value {
+    let sign = bits & fmt.sign_bit() != 0;
+    let exp = (bits >> fmt.frac_bits) & fmt.exp_mask();
+    let frac = bits & fmt.frac_mask();
+    if exp == fmt.exp_mask() {
+        if frac == 0 {
+            return Value::Inf(sign);
+        }
+        return Value::Nan { signaling: frac & (1 << (fmt.frac_bits - 1)) == 0 };
+    }
+    if exp == 0 {
+        if frac == 0 {
+            return Value::Zero(sign);
+        }
+        let shift = frac.leading_zeros() as i32 - (63 - fmt.frac_bits as i32);
+        return Value::Finite(sign, (frac as u128) << shift, fmt.emin() - fmt.frac_bits as i32 - shift);
+    }
+    Value::Finite(
+        sign,
+        (frac | (1 << fmt.frac_bits)) as u128,
+        exp as i32 - fmt.bias() - fmt.frac_bits as i32,
+    )
+}
+
+fn is_nan(value: Value) -> bool {
+    matches!(value, Value::Nan { .. })
+}
+
+fn is_snan(value: Value) -> bool {
+    matches!(value, Value::Nan { signaling: true })
+}
+
+// Rounds `(sig + sticky) * 2^exp` to an integer multiple of `2^lsb_exp`.
+// `sticky` stands for a nonzero fraction below the lsb of `sig`.
+// Returns the rounded multiple and whether the result is inexact.
+fn round_at(sig: u128, exp: i32, sticky: bool, lsb_exp: i32, sign: bool, rm: u64) -> (u128, bool) {
+    let shift = lsb_exp - exp;
+    if shift <= 0 {
+        debug_assert!(!sticky);
+        return (sig << -shift, false);
+    }
+    let (kept, half_cmp, inexact) = if shift >= 128 {
+        (0, Ordering::Less, sig != 0 || sticky)
+    } else {
+        let dropped = sig & ((1 << shift) - 1);
+        let half = 1 << (shift - 1);
+        let half_cmp = match dropped.cmp(&half) {
+            Ordering::Equal if sticky => Ordering::Greater,
+            ord => ord,
+        };
+        (sig >> shift, half_cmp, dropped != 0 || sticky)
+    };
+    let increment = match rm {
+        RM_RNE => half_cmp == Ordering::Greater || (half_cmp == Ordering::Equal && kept & 1 == 1),
+        RM_RTZ => false,
+        RM_RDN => inexact && sign,
+        RM_RUP => inexact && !sign,
+        RM_RMM => half_cmp != Ordering::Less,
+        _ => false,
+    };
+    (kept + increment as u128, inexact)
+}
+
+// Rounds the nonzero value `(sig + sticky) * 2^exp` into `fmt`, raising
+// NX/UF/OF as needed. Tininess is detected after rounding, as RISC-V requires.
+fn round_pack(fmt: Format, sign: bool, sig: u128, exp: i32, sticky: bool, rm: u64, flags: &mut u64) -> u64 {
+    let frac_bits = fmt.frac_bits as i32;
+    let msb = 127 - sig.leading_zeros() as i32;
+    let e = exp + msb;
+    let mut lsb_exp = e.max(fmt.emin()) - frac_bits;
+    let (mut kept, inexact) = round_at(sig, exp, sticky, lsb_exp, sign, rm);
+    if kept == 1 << (frac_bits + 1) {
+        kept >>= 1;
+        lsb_exp += 1;
+    }
+    if inexact {
+        *flags |= FFLAGS_NX;
+        if e < fmt.emin() {
+            let (unbounded, _) = round_at(sig, exp, sticky, e - frac_bits, sign, rm);
+            if !(e == fmt.emin() - 1 && unbounded == 1 << (frac_bits + 1)) {
+                *flags |= FFLAGS_UF;
+            }
+        }
+    }
+    if kept >> frac_bits == 0 {
+        // subnormal or zero
+        return fmt.zero(sign) | kept as u64;
+    }
+    let biased = (lsb_exp + frac_bits + fmt.bias()) as u64;
+    if biased >= fmt.exp_mask() {
+        *flags |= FFLAGS_OF | FFLAGS_NX;
+        return match rm {
+            RM_RTZ => fmt.max_finite(sign),
+            RM_RDN if !sign => fmt.max_finite(sign),
+            RM_RUP if sign => fmt.max_finite(sign),
+            _ => fmt.infinity(sign),
+        };
+    }
+    fmt.zero(sign) | (biased << fmt.frac_bits) | (kept as u64 & fmt.frac_mask())
+}
+
+// Adds two nonzero finite values given as `sig * 2^exp`, where each `sig` is below 2^126.
+#[allow(clippy::too_many_arguments)]
+fn add_finite(fmt: Format, sa: bool, ma: u128, ea: i32, sb: bool, mb: u128, eb: i32, rm: u64, flags: &mut u64) -> u64 {
+    // line both significands up with their msb at bit 125
+    let norm = |m: u128, e: i32| {
+        let shift = m.leading_zeros() as i32 - 2;
+        (m << shift, e - shift)
+    };
+    let (ma, ea) = norm(ma, ea);
+    let (mb, eb) = norm(mb, eb);
+    let ((sbig, big, ebig), (ssmall, small, esmall)) = if (ea, ma) >= (eb, mb) {
+        ((sa, ma, ea), (sb, mb, eb))
+    } else {
+        ((sb, mb, eb), (sa, ma, ea))
+    };
+    let diff = (ebig - esmall) as u32;
+    let (small, sticky) = if diff >= 128 {
+        (0, true)
+    } else {
+        (small >> diff, small & ((1 << diff) - 1) != 0)
+    };
+    let sig = if sbig == ssmall {
+        big + small
+    } else {
+        big - small - sticky as u128
+    };
+    if sig == 0 && !sticky {
+        return fmt.zero(rm == RM_RDN);
+    }
+    round_pack(fmt, sbig, sig, ebig, sticky, rm, flags)
+}
+
+fn nan_result(fmt: Format, operands: &[Value], flags: &mut u64) -> u64 {
+    if operands.iter().any(|v| is_snan(*v)) {
+        *flags |= FFLAGS_NV;
+    }
+    fmt.canonical_nan()
+}
+
+fn invalid(fmt: Format, flags: &mut u64) -> u64 {
+    *flags |= FFLAGS_NV;
+    fmt.canonical_nan()
+}
+
+pub fn add(fmt: Format, a: u64, b: u64, rm: u64, flags: &mut u64) -> u64 {
+    let (va, vb) = (unpack(fmt, a), unpack(fmt, b));
+    match (va, vb) {
+        _ if is_nan(va) || is_nan(vb) => nan_result(fmt, &[va, vb], flags),
+        (Value::Inf(x), Value::Inf(y)) if x != y => invalid(fmt, flags),
+        (Value::Inf(x), _) | (_, Value::Inf(x)) => fmt.infinity(x),
+        (Value::Zero(x), Value::Zero(y)) => fmt.zero(if x == y { x } else { rm == RM_RDN }),
+        (Value::Zero(_), _) => b,
+        (_, Value::Zero(_)) => a,
+        (Value::Finite(sa, ma, ea), Value::Finite(sb, mb, eb)) => add_finite(fmt, sa, ma, ea, sb, mb, eb, rm, flags),
+        _ => unreachable!(),
+    }
+}
+
+pub fn sub(fmt: Format, a: u64, b: u64, rm: u64, flags: &mut u64) -> u64 {
+    add(fmt, a, b ^ fmt.sign_bit(), rm, flags)
+}
+
+pub fn mul(fmt: Format, a: u64, b: u64, rm: u64, flags: &mut u64) -> u64 {
+    let (va, vb) = (unpack(fmt, a), unpack(fmt, b));
+    match (va, vb) {
+        _ if is_nan(va) || is_nan(vb) => nan_result(fmt, &[va, vb], flags),
+        (Value::Inf(_), Value::Zero(_)) | (Value::Zero(_), Value::Inf(_)) => invalid(fmt, flags),
+        (Value::Inf(x), Value::Inf(y))
+        | (Value::Inf(x), Value::Finite(y, _, _))
+        | (Value::Finite(x, _, _), Value::Inf(y)) => fmt.infinity(x != y),
+        (Value::Zero(x), Value::Zero(y))
+        | (Value::Zero(x), Value::Finite(y, _, _))
+        | (Value::Finite(x, _, _), Value::Zero(y)) => fmt.zero(x != y),
+        (Value::Finite(sa, ma, ea), Value::Finite(sb, mb, eb)) => round_pack(fmt, sa != sb, ma * mb, ea + eb, false, rm, flags),
+        _ => unreachable!(),
+    }
+}
+
+pub fn div(fmt: Format, a: u64, b: u64, rm: u64, flags: &mut u64) -> u64 {
+    let (va, vb) = (unpack(fmt, a), unpack(fmt, b));
+    match (va, vb) {
+        _ if is_nan(va) || is_nan(vb) => nan_result(fmt, &[va, vb], flags),
+        (Value::Inf(_), Value::Inf(_)) | (Value::Zero(_), Value::Zero(_)) => invalid(fmt, flags),
+        (Value::Inf(x), Value::Zero(y)) | (Value::Inf(x), Value::Finite(y, _, _)) => fmt.infinity(x != y),
+        (Value::Zero(x), Value::Inf(y))
+        | (Value::Finite(x, _, _), Value::Inf(y))
+        | (Value::Zero(x), Value::Finite(y, _, _)) => fmt.zero(x != y),
+        (Value::Finite(x, _, _), Value::Zero(y)) => {
+            *flags |= FFLAGS_DZ;
+            fmt.infinity(x != y)
+        }
+        (Value::Finite(sa, ma, ea), Value::Finite(sb, mb, eb)) => {
+            let dividend = ma << 64;
+            let quotient = dividend / mb;
+            let sticky = dividend % mb != 0;
+            round_pack(fmt, sa != sb, quotient, ea - eb - 64, sticky, rm, flags)
+        }
+        _ => unreachable!(),
+    }
+}
+
+// Integer square root; returns the root and whether a remainder was left.
+fn isqrt(n: u128) -> (u128, bool) {
+    let mut rem = n;
+    let mut root = 0u128;
+    let mut one = 1u128 << 126;
+    while one > rem {
+        one >>= 2;
+    }
+    while one != 0 {
+        if rem >= root + one {
+            rem -= root + one;
+            root = (root >> 1) + one;
+        } else {
+            root >>= 1;
+        }
+        one >>= 2;
+    }
+    (root, rem != 0)
+}
+
+pub fn sqrt(fmt: Format, a: u64, rm: u64, flags: &mut u64) -> u64 {
+    match unpack(fmt, a) {
+        va @ Value::Nan { .. } => nan_result(fmt, &[va], flags),
+        Value::Zero(_) => a,
+        Value::Inf(false) => a,
+        Value::Inf(true) | Value::Finite(true, _, _) => invalid(fmt, flags),
+        Value::Finite(false, mut m, mut e) => {
+            if e & 1 != 0 {
+                m <<= 1;
+                e -= 1;
+            }
+            let (root, sticky) = isqrt(m << 70);
+            round_pack(fmt, false, root, (e - 70) / 2, sticky, rm, flags)
+        }
+    }
+}
+
+// Computes `(±a * b) ± c` with a single rounding.
+#[allow(clippy::too_many_arguments)]
+pub fn fma(fmt: Format, a: u64, b: u64, c: u64, negate_product: bool, negate_addend: bool, rm: u64, flags: &mut u64) -> u64 {
+    let (va, vb, vc) = (unpack(fmt, a), unpack(fmt, b), unpack(fmt, c));
+    let inf_times_zero = matches!(
+        (va, vb),
+        (Value::Inf(_), Value::Zero(_)) | (Value::Zero(_), Value::Inf(_))
+    );
+    if is_nan(va) || is_nan(vb) || is_nan(vc) {
+        // inf * 0 is invalid even when the addend is a quiet NaN
+        if inf_times_zero {
+            *flags |= FFLAGS_NV;
+        }
+        return nan_result(fmt, &[va, vb, vc], flags);
+    }
+    if inf_times_zero {
+        return invalid(fmt, flags);
+    }
+    let sign_of = |v: Value| match v {
+        Value::Inf(s) | Value::Zero(s) | Value::Finite(s, _, _) => s,
+        Value::Nan { .. } => false,
+    };
+    let product_sign = (sign_of(va) != sign_of(vb)) != negate_product;
+    let addend_sign = sign_of(vc) != negate_addend;
+    let c = (c & !fmt.sign_bit()) | fmt.zero(addend_sign);
+    match (va, vb, vc) {
+        (Value::Inf(_), _, _) | (_, Value::Inf(_), _) => match vc {
+            Value::Inf(_) if addend_sign != product_sign => invalid(fmt, flags),
+            _ => fmt.infinity(product_sign),
+        },
+        (_, _, Value::Inf(_)) => c,
+        (Value::Zero(_), _, Value::Zero(_)) | (_, Value::Zero(_), Value::Zero(_)) => {
+            fmt.zero(if product_sign == addend_sign { product_sign } else { rm == RM_RDN })
+        }
+        (Value::Zero(_), _, _) | (_, Value::Zero(_), _) => c,
+        (Value::Finite(_, ma, ea), Value::Finite(_, mb, eb), Value::Zero(_)) => {
+            round_pack(fmt, product_sign, ma * mb, ea + eb, false, rm, flags)
+        }
+        (Value::Finite(_, ma, ea), Value::Finite(_, mb, eb), Value::Finite(_, mc, ec)) => {
+            add_finite(fmt, product_sign, ma * mb, ea + eb, addend_sign, mc, ec, rm, flags)
+        }
+        _ => unreachable!(),
+    }
+}
+
+// Converts between single and double precision.
+pub fn convert(from: Format, to: Format, a: u64, rm: u64, flags: &mut u64) -> u64 {
+    match unpack(from, a) {
+        va @ Value::Nan { .. } => nan_result(to, &[va], flags),
+        Value::Inf(s) => to.infinity(s),
+        Value::Zero(s) => to.zero(s),
+        Value::Finite(s, m, e) => round_pack(to, s, m, e, false, rm, flags),
+    }
+}
+
+// Converts to a `width`-bit integer, saturating and raising NV on overflow
+// or NaN. 32-bit results come back sign-extended to 64 bits.
+pub fn to_int(fmt: Format, a: u64, signed: bool, width: u32, rm: u64, flags: &mut u64) -> u64 {
+    let (min, max): (i128, i128) = match signed {
+        true => (-(1 << (width - 1)), (1 << (width - 1)) - 1),
+        false => (0, (1 << width) - 1),
+    };
+    let (value, inexact) = match unpack(fmt, a) {
+        Value::Nan { .. } | Value::Inf(false) => (max, false),
+        Value::Inf(true) => (min, false),
+        Value::Zero(_) => (0, false),
+        Value::Finite(s, m, e) => {
+            let (magnitude, inexact) = if e >= 0 {
+                // anything shifted this far is out of range for 64 bits anyway
+                (if e > 64 { 1 << 65 } else { m << e }, false)
+            } else {
+                round_at(m, e, false, 0, s, rm)
+            };
+            let magnitude = magnitude.min(1 << 65) as i128;
+            (if s { -magnitude } else { magnitude }, inexact)
+        }
+    };
+    let nan_or_inf = matches!(unpack(fmt, a), Value::Nan { .. } | Value::Inf(_));
+    let result = if nan_or_inf || value < min || value > max {
+        *flags |= FFLAGS_NV;
+        value.clamp(min, max)
+    } else {
+        if inexact {
+            *flags |= FFLAGS_NX;
+        }
+        value
+    };
+    match width {
+        32 => result as i32 as i64 as u64,
+        _ => result as u64,
+    }
+}
+
+// Converts a `width`-bit integer held in `value` to `fmt`.
+pub fn from_int(fmt: Format, value: u64, signed: bool, width: u32, rm: u64, flags: &mut u64) -> u64 {
+    let value: i128 = match (signed, width) {
+        (true, 32) => value as i32 as i128,
+        (false, 32) => value as u32 as i128,
+        (true, _) => value as i64 as i128,
+        (false, _) => value as i128,
+    };
+    if value == 0 {
+        return 0;
+    }
+    round_pack(fmt, value < 0, value.unsigned_abs(), 0, false, rm, flags)
+}
+
+// Orders non-NaN values, treating -0 as below +0 when `signed_zero` is set.
+fn order_key(fmt: Format, bits: u64, signed_zero: bool) -> i128 {
+    let magnitude = (bits & !fmt.sign_bit()) as i128;
+    match bits & fmt.sign_bit() != 0 {
+        true if signed_zero => -magnitude - 1,
+        true => -magnitude,
+        false => magnitude,
+    }
+}
+
+// feq is a quiet comparison; flt and fle are signaling.
+pub fn compare(fmt: Format, a: u64, b: u64, signaling: bool, flags: &mut u64) -> Option<Ordering> {
+    let (va, vb) = (unpack(fmt, a), unpack(fmt, b));
+    if is_nan(va) || is_nan(vb) {
+        if signaling || is_snan(va) || is_snan(vb) {
+            *flags |= FFLAGS_NV;
+        }
+        return None;
+    }
+    Some(order_key(fmt, a, false).cmp(&order_key(fmt, b, false)))
+}
+
+// IEEE 754-2019 minimumNumber/maximumNumber.
+pub fn min_max(fmt: Format, a: u64, b: u64, max: bool, flags: &mut u64) -> u64 {
+    let (va, vb) = (unpack(fmt, a), unpack(fmt, b));
+    if is_snan(va) || is_snan(vb) {
+        *flags |= FFLAGS_NV;
+    }
+    match (is_nan(va), is_nan(vb)) {
+        (true, true) => fmt.canonical_nan(),
+        (true, false) => b,
+        (false, true) => a,
+        _ => {
+            let a_less = order_key(fmt, a, true) < order_key(fmt, b, true);
+            if a_less != max { a } else { b }
+        }
+    }
+}
+
+pub fn classify(fmt: Format, a: u64) -> u64 {
+    let subnormal = (a >> fmt.frac_bits) & fmt.exp_mask() == 0;
+    let shift = match unpack(fmt, a) {
+        Value::Inf(true) => 0,
+        Value::Finite(true, _, _) if !subnormal => 1,
+        Value::Finite(true, _, _) => 2,
+        Value::Zero(true) => 3,
+        Value::Zero(false) => 4,
+        Value::Finite(false, _, _) if subnormal => 5,
+        Value::Finite(false, _, _) => 6,
+        Value::Inf(false) => 7,
+        Value::Nan { signaling: true } => 8,
+        Value::Nan { signaling: false } => 9,
+    };
+    1 << shift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONE: u64 = 0x3f800000;
+    const TWO: u64 = 0x40000000;
+    const HALF_ULP_OF_ONE: u64 = 0x33800000;
+    const MAX: u64 = 0x7f7fffff;
+    const INF: u64 = 0x7f800000;
+    const NEG: u64 = 0x80000000;
+    const QNAN: u64 = 0x7fc00000;
+
+    const MODES: [u64; 5] = [RM_RNE, RM_RTZ, RM_RDN, RM_RUP, RM_RMM];
+
+    // Runs `op` with fresh flags and returns the result along with them.
+    fn run(op: impl FnOnce(&mut u64) -> u64) -> (u64, u64) {
+        let mut flags = 0;
+        let result = op(&mut flags);
+        (result, flags)
+    }
+
+    #[test]
+    fn each_mode_rounds_a_tie() {
+        // 1 + 2^-24 sits halfway between 1 and the next single up
+        let expected = [ONE, ONE, ONE, ONE + 1, ONE + 1];
+        for (rm, want) in MODES.into_iter().zip(expected) {
+            assert_eq!(run(|f| add(F32, ONE, HALF_ULP_OF_ONE, rm, f)), (want, FFLAGS_NX), "rm {rm}");
+        }
+        let expected = [NEG | ONE, NEG | ONE, NEG | (ONE + 1), NEG | ONE, NEG | (ONE + 1)];
+        for (rm, want) in MODES.into_iter().zip(expected) {
+            let got = run(|f| add(F32, NEG | ONE, NEG | HALF_ULP_OF_ONE, rm, f));
+            assert_eq!(got, (want, FFLAGS_NX), "rm {rm}");
+        }
+        // with an odd lsb, ties-to-even goes up
+        assert_eq!(run(|f| add(F32, ONE + 1, HALF_ULP_OF_ONE, RM_RNE, f)), (ONE + 2, FFLAGS_NX));
+    }
+
+    #[test]
+    fn each_mode_rounds_a_carry_into_the_exponent() {
+        // the largest single below 2, plus half an ulp
+        let below_two = TWO - 1;
+        let expected = [TWO, below_two, below_two, TWO, TWO];
+        for (rm, want) in MODES.into_iter().zip(expected) {
+            assert_eq!(run(|f| add(F32, below_two, HALF_ULP_OF_ONE, rm, f)), (want, FFLAGS_NX), "rm {rm}");
+        }
+    }
+
+    #[test]
+    fn overflow_depends_on_mode_and_sign() {
+        let positive = [INF, MAX, MAX, INF, INF];
+        let negative = [NEG | INF, NEG | MAX, NEG | INF, NEG | MAX, NEG | INF];
+        for ((rm, pos), neg) in MODES.into_iter().zip(positive).zip(negative) {
+            assert_eq!(run(|f| mul(F32, MAX, TWO, rm, f)), (pos, FFLAGS_OF | FFLAGS_NX), "rm {rm}");
+            assert_eq!(run(|f| mul(F32, NEG | MAX, TWO, rm, f)), (neg, FFLAGS_OF | FFLAGS_NX), "rm {rm}");
+        }
+    }
+
+    #[test]
+    fn subnormal_results_raise_underflow_only_when_inexact() {
+        let min_normal = 0x00800000;
+        let half = 0x3f000000;
+        // 2^-127 is an exact subnormal
+        assert_eq!(run(|f| mul(F32, min_normal, half, RM_RNE, f)), (0x00400000, 0));
+        // half the smallest subnormal is a tie between it and zero
+        assert_eq!(run(|f| mul(F32, 1, half, RM_RNE, f)), (0, FFLAGS_UF | FFLAGS_NX));
+        assert_eq!(run(|f| mul(F32, 1, half, RM_RUP, f)), (1, FFLAGS_UF | FFLAGS_NX));
+        assert_eq!(run(|f| mul(F32, NEG | 1, half, RM_RDN, f)), (NEG | 1, FFLAGS_UF | FFLAGS_NX));
+    }
+
+    #[test]
+    fn tininess_is_detected_after_rounding() {
+        let min_normal = 0x00800000;
+        // 2^-126 * (1 - 2^-25) rounds to 2^-126 even with an unbounded exponent
+        let not_tiny = 0x380ffffff0000000;
+        assert_eq!(run(|f| convert(F64, F32, not_tiny, RM_RNE, f)), (min_normal, FFLAGS_NX));
+        // 2^-126 * (1 - 2^-24) fits 24 bits, so it is tiny before it rounds up
+        let tiny = 0x380fffffe0000000;
+        assert_eq!(run(|f| convert(F64, F32, tiny, RM_RNE, f)), (min_normal, FFLAGS_UF | FFLAGS_NX));
+    }
+
+    #[test]
+    fn signed_zero_rules() {
+        // x - x is +0, except -0 when rounding down
+        for rm in MODES {
+            let want = if rm == RM_RDN { NEG } else { 0 };
+            assert_eq!(run(|f| add(F32, ONE, NEG | ONE, rm, f)), (want, 0), "rm {rm}");
+            assert_eq!(run(|f| sub(F32, ONE, ONE, rm, f)), (want, 0), "rm {rm}");
+            assert_eq!(run(|f| add(F32, 0, NEG, rm, f)), (want, 0), "rm {rm}");
+            assert_eq!(run(|f| add(F32, NEG, NEG, rm, f)), (NEG, 0), "rm {rm}");
+        }
+        assert_eq!(run(|f| mul(F32, NEG, TWO, RM_RNE, f)), (NEG, 0));
+        assert_eq!(run(|f| mul(F32, NEG, NEG | TWO, RM_RNE, f)), (0, 0));
+        assert_eq!(run(|f| div(F32, 0, NEG | TWO, RM_RNE, f)), (NEG, 0));
+        assert_eq!(run(|f| sqrt(F32, NEG, RM_RNE, f)), (NEG, 0));
+        // a zero product keeps the addend's sign only when both agree
+        assert_eq!(run(|f| fma(F32, NEG, ONE, NEG, false, false, RM_RNE, f)), (NEG, 0));
+        assert_eq!(run(|f| fma(F32, NEG, ONE, 0, false, false, RM_RNE, f)), (0, 0));
+        assert_eq!(run(|f| fma(F32, NEG, ONE, 0, false, false, RM_RDN, f)), (NEG, 0));
+    }
+
+    #[test]
+    fn fcvt_w_saturates_and_raises_invalid() {
+        let int_min = i32::MIN as i64 as u64;
+        let int_max = i32::MAX as u64;
+        let big = 0x4f32d05e; // 3e9
+        for rm in MODES {
+            assert_eq!(run(|f| to_int(F32, big, true, 32, rm, f)), (int_max, FFLAGS_NV), "rm {rm}");
+            assert_eq!(run(|f| to_int(F32, NEG | big, true, 32, rm, f)), (int_min, FFLAGS_NV), "rm {rm}");
+        }
+        assert_eq!(run(|f| to_int(F32, INF, true, 32, RM_RNE, f)), (int_max, FFLAGS_NV));
+        assert_eq!(run(|f| to_int(F32, NEG | INF, true, 32, RM_RNE, f)), (int_min, FFLAGS_NV));
+        // NaN saturates to the largest value, whatever its sign
+        assert_eq!(run(|f| to_int(F32, NEG | QNAN, true, 32, RM_RNE, f)), (int_max, FFLAGS_NV));
+        // 2^31 - 0.5 only overflows once it rounds up
+        let just_below = 0x41dfffffffe00000;
+        assert_eq!(run(|f| to_int(F64, just_below, true, 32, RM_RNE, f)), (int_max, FFLAGS_NV));
+        assert_eq!(run(|f| to_int(F64, just_below, true, 32, RM_RTZ, f)), (int_max, FFLAGS_NX));
+    }
+
+    #[test]
+    fn fcvt_wu_saturates_and_raises_invalid() {
+        // 32-bit results are sign-extended, so u32::MAX reads back as all ones
+        assert_eq!(run(|f| to_int(F32, INF, false, 32, RM_RNE, f)), (u64::MAX, FFLAGS_NV));
+        assert_eq!(run(|f| to_int(F32, QNAN, false, 32, RM_RNE, f)), (u64::MAX, FFLAGS_NV));
+        assert_eq!(run(|f| to_int(F32, NEG | INF, false, 32, RM_RNE, f)), (0, FFLAGS_NV));
+        assert_eq!(run(|f| to_int(F32, NEG | ONE, false, 32, RM_RNE, f)), (0, FFLAGS_NV));
+        // a small negative value that rounds to zero is only inexact
+        let neg_half = 0xbf000000;
+        assert_eq!(run(|f| to_int(F32, neg_half, false, 32, RM_RTZ, f)), (0, FFLAGS_NX));
+        assert_eq!(run(|f| to_int(F32, neg_half, false, 32, RM_RDN, f)), (0, FFLAGS_NV));
+        assert_eq!(run(|f| to_int(F32, NEG, false, 32, RM_RNE, f)), (0, 0));
+    }
+
+    #[test]
+    fn fcvt_w_rounds_by_mode() {
+        let two_and_a_half = 0x40200000;
+        let expected = [2, 2, 2, 3, 3];
+        for (rm, want) in MODES.into_iter().zip(expected) {
+            assert_eq!(run(|f| to_int(F32, two_and_a_half, true, 32, rm, f)), (want, FFLAGS_NX), "rm {rm}");
+        }
+        let expected = [-2i64, -2, -3, -2, -3];
+        for (rm, want) in MODES.into_iter().zip(expected) {
+            let got = run(|f| to_int(F32, NEG | two_and_a_half, true, 32, rm, f));
+            assert_eq!(got, (want as u64, FFLAGS_NX), "rm {rm}");
+        }
+    }
+
+    #[test]
+    fn fma_inf_times_zero_is_invalid_even_with_a_quiet_nan_addend() {
+        let nan = F32.canonical_nan();
+        assert_eq!(run(|f| fma(F32, INF, 0, QNAN, false, false, RM_RNE, f)), (nan, FFLAGS_NV));
+        assert_eq!(run(|f| fma(F32, 0, NEG | INF, QNAN, false, false, RM_RNE, f)), (nan, FFLAGS_NV));
+        assert_eq!(run(|f| fma(F32, INF, 0, ONE, false, false, RM_RNE, f)), (nan, FFLAGS_NV));
+        // a quiet NaN addend alone propagates without raising anything
+        assert_eq!(run(|f| fma(F32, ONE, ONE, QNAN, false, false, RM_RNE, f)), (nan, 0));
+    }
+}
@@ -0,0 +1,350 @@
+// A GDB remote serial protocol stub, so `gdb` can attach to a running Cpu
+// with `target remote localhost:<port>`.
+
+use std::collections::HashSet;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::cpu::*;
+use crate::dram::*;
+use crate::machine::*;
+use crate::mmu::*;
+
+// The register number gdb uses for pc, after x0-x31.
+const PC_REGNUM: usize = 32;
+
+// How many instructions run between checks for a Ctrl-C from gdb.
+const INTERRUPT_POLL_INTERVAL: u64 = 1024;
+
+const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "fp", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+// How a debugging session ended.
+#[derive(Debug)]
+pub enum SessionEnd {
+    // gdb detached; the guest should keep running without it.
+    Detached,
+    // gdb killed the guest or hung up.
+    Killed,
+    // the guest halted or exited while gdb was attached
+    Finished(StopReason),
+}
+
+pub struct GdbStub {
+    stream: TcpStream,
+    breakpoints: HashSet<u64>,
+}
+
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\"><target version=\"1.0\">\
+         <architecture>riscv:rv64</architecture><feature name=\"org.gnu.gdb.riscv.cpu\">",
+    );
+    for (i, name) in ABI_NAMES.iter().enumerate() {
+        let kind = match i {
+            1 => "code_ptr",
+            2 | 8 => "data_ptr",
+            _ => "int",
+        };
+        xml += &format!("<reg name=\"{}\" bitsize=\"64\" type=\"{}\" regnum=\"{}\"/>", name, kind, i);
+    }
+    xml += &format!("<reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\" regnum=\"{}\"/>", PC_REGNUM);
+    xml += "</feature></target>";
+    xml
+}
+
+// Registers travel as target-endian (little-endian) hex bytes.
+fn encode_u64(value: u64) -> String {
+    value.to_le_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn decode_u64(hex: &str) -> Option<u64> {
+    let bytes = decode_hex(hex)?;
+    let mut value = [0; 8];
+    value.get_mut(..bytes.len())?.copy_from_slice(&bytes);
+    Some(u64::from_le_bytes(value))
+}
+
+// Translates `vaddr` for gdb, refusing addresses outside RAM: reading or
+// writing a device register can have side effects, such as claiming a PLIC
+// interrupt or consuming a UART byte.
+fn debug_address(cpu: &mut Cpu, vaddr: u64, access: AccessType) -> Option<u64> {
+    let paddr = cpu.debug_translate(vaddr, access).ok()?;
+    let size = cpu.bus.device::<Dram>()?.size();
+    (paddr.checked_sub(DRAM_BASE)? < size).then_some(paddr)
+}
+
+// Parses an "addr,length" pair.
+fn parse_range(args: &str) -> Option<(u64, u64)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((u64::from_str_radix(addr, 16).ok()?, u64::from_str_radix(len, 16).ok()?))
+}
+
+impl GdbStub {
+    // Waits for gdb to connect on localhost:`port`.
+    pub fn listen(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        eprintln!("Waiting for gdb on localhost:{}", port);
+        let (stream, addr) = listener.accept()?;
+        eprintln!("gdb connected from {}", addr);
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream,
+            breakpoints: HashSet::new(),
+        })
+    }
+
+    // Serves gdb requests until it detaches, kills the guest or disconnects.
+    pub fn serve(&mut self, machine: &mut Machine) -> io::Result<SessionEnd> {
+        while let Some(packet) = self.read_packet()? {
+            let (command, args) = packet.split_at(packet.chars().next().map_or(0, |c| c.len_utf8()));
+            let cpu = &mut machine.cpu;
+            let reply = match command {
+                "?" => "S05".to_string(),
+                "\x03" => "S02".to_string(),
+                "g" => {
+                    let mut regs: String = cpu.registers.iter().map(|r| encode_u64(*r)).collect();
+                    regs += &encode_u64(cpu.pc);
+                    regs
+                }
+                "G" => self.write_registers(cpu, args),
+                "p" => match usize::from_str_radix(args, 16) {
+                    Ok(n) if n < 32 => encode_u64(cpu.registers[n]),
+                    Ok(PC_REGNUM) => encode_u64(cpu.pc),
+                    _ => "E01".to_string(),
+                },
+                "P" => self.write_register(cpu, args),
+                "m" => self.read_memory(cpu, args),
+                "M" => self.write_memory(cpu, args),
+                "c" | "s" => {
+                    if let Ok(addr) = u64::from_str_radix(args, 16) {
+                        cpu.pc = addr;
+                    }
+                    let (reply, finished) = self.resume(machine, command == "s")?;
+                    if let Some(reason) = finished {
+                        self.send_packet(&reply)?;
+                        return Ok(SessionEnd::Finished(reason));
+                    }
+                    reply
+                }
+                "Z" | "z" => self.update_breakpoint(command == "Z", args),
+                "q" => self.query(args),
+                "H" => "OK".to_string(),
+                "T" => "OK".to_string(),
+                "D" => {
+                    self.send_packet("OK")?;
+                    return Ok(SessionEnd::Detached);
+                }
+                "k" => return Ok(SessionEnd::Killed),
+                // anything else is unsupported, which an empty reply tells gdb
+                _ => String::new(),
+            };
+            self.send_packet(&reply)?;
+        }
+        Ok(SessionEnd::Killed)
+    }
+
+    fn query(&self, args: &str) -> String {
+        if args.starts_with("Supported") {
+            return "PacketSize=4000;qXfer:features:read+;swbreak+".to_string();
+        }
+        if let Some(annex) = args.strip_prefix("Xfer:features:read:target.xml:") {
+            let xml = target_xml();
+            let Some((offset, length)) = parse_range(annex) else {
+                return "E01".to_string();
+            };
+            let start = (offset as usize).min(xml.len());
+            let end = (start + length as usize).min(xml.len());
+            let marker = if end == xml.len() { 'l' } else { 'm' };
+            return format!("{}{}", marker, &xml[start..end]);
+        }
+        match args {
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn write_registers(&self, cpu: &mut Cpu, args: &str) -> String {
+        for (i, chunk) in args.as_bytes().chunks(16).enumerate().take(PC_REGNUM + 1) {
+            let Some(value) = std::str::from_utf8(chunk).ok().and_then(decode_u64) else {
+                return "E01".to_string();
+            };
+            match i {
+                PC_REGNUM => cpu.pc = value,
+                _ => cpu.registers[i] = value,
+            }
+        }
+        cpu.registers[0] = 0;
+        "OK".to_string()
+    }
+
+    fn write_register(&self, cpu: &mut Cpu, args: &str) -> String {
+        let Some((reg, value)) = args.split_once('=') else {
+            return "E01".to_string();
+        };
+        match (usize::from_str_radix(reg, 16), decode_u64(value)) {
+            (Ok(0), Some(_)) => {}
+            (Ok(n), Some(value)) if n < 32 => cpu.registers[n] = value,
+            (Ok(PC_REGNUM), Some(value)) => cpu.pc = value,
+            _ => return "E01".to_string(),
+        }
+        "OK".to_string()
+    }
+
+    // Memory is addressed the way the guest currently sees it, but without
+    // the side effects of a guest access. Only RAM can be accessed.
+    fn read_memory(&self, cpu: &mut Cpu, args: &str) -> String {
+        let Some((addr, len)) = parse_range(args) else {
+            return "E01".to_string();
+        };
+        let mut data = String::new();
+        for i in 0..len {
+            let byte = debug_address(cpu, addr.wrapping_add(i), AccessType::Load)
+                .and_then(|paddr| cpu.bus.load(paddr, 8).ok());
+            match byte {
+                Some(byte) => data += &format!("{:02x}", byte),
+                // a partial read is fine as long as something came back
+                None if i > 0 => break,
+                None => return "E14".to_string(),
+            }
+        }
+        data
+    }
+
+    fn write_memory(&self, cpu: &mut Cpu, args: &str) -> String {
+        let Some((range, hex)) = args.split_once(':') else {
+            return "E01".to_string();
+        };
+        let (Some((addr, _)), Some(bytes)) = (parse_range(range), decode_hex(hex)) else {
+            return "E01".to_string();
+        };
+        for (i, byte) in bytes.iter().enumerate() {
+            let written = debug_address(cpu, addr.wrapping_add(i as u64), AccessType::Store)
+                .and_then(|paddr| cpu.bus.store(paddr, 8, *byte as u64).ok());
+            if written.is_none() {
+                return "E14".to_string();
+            }
+        }
+        "OK".to_string()
+    }
+
+    // Only software breakpoints (type 0) are supported. They are kept in a
+    // table rather than patched into guest memory.
+    fn update_breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+        let (Some("0"), Some(addr)) = (fields.next(), fields.next()) else {
+            return String::new();
+        };
+        let Ok(addr) = u64::from_str_radix(addr, 16) else {
+            return "E01".to_string();
+        };
+        if insert {
+            self.breakpoints.insert(addr);
+        } else {
+            self.breakpoints.remove(&addr);
+        }
+        "OK".to_string()
+    }
+
+    // Runs the guest until a breakpoint, a Ctrl-C from gdb, the end of a
+    // single step, or the guest stopping, and returns the stop reply. If the
+    // guest halted or exited, its stop reason comes back too.
+    fn resume(&mut self, machine: &mut Machine, single_step: bool) -> io::Result<(String, Option<StopReason>)> {
+        self.stream.set_nonblocking(true)?;
+        let mut executed: u64 = 0;
+        let stop = loop {
+            if executed > 0 && self.breakpoints.contains(&machine.pc()) {
+                break ("T05swbreak:;".to_string(), None);
+            }
+            match machine.step() {
+                Some(StopReason::Halted) => break ("W00".to_string(), Some(StopReason::Halted)),
+                Some(StopReason::Exited(code)) => break (format!("W{:02x}", code), Some(StopReason::Exited(code))),
+                // the trap has been taken; report it and let gdb look around
+                Some(StopReason::Trap { .. }) => break ("S0b".to_string(), None),
+                _ => {}
+            }
+            executed += 1;
+            if single_step {
+                break ("S05".to_string(), None);
+            }
+            if executed.is_multiple_of(INTERRUPT_POLL_INTERVAL) {
+                let mut byte = [0; 1];
+                match self.stream.read(&mut byte) {
+                    Ok(1) if byte[0] == 0x03 => break ("S02".to_string(), None),
+                    Ok(0) => return Err(io::Error::new(ErrorKind::ConnectionAborted, "gdb disconnected")),
+                    Ok(_) => {}
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+        self.stream.set_nonblocking(false)?;
+        Ok(stop)
+    }
+
+    // Reads the next packet, acknowledging it. A bare Ctrl-C comes back as
+    // "\x03". Returns None once gdb hangs up.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        let mut byte = [0; 1];
+        loop {
+            if self.stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            match byte[0] {
+                0x03 => return Ok(Some("\x03".to_string())),
+                b'$' => break,
+                // acks and anything else between packets
+                _ => {}
+            }
+        }
+        let mut data = Vec::new();
+        loop {
+            if self.stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            match byte[0] {
+                b'#' => break,
+                // '}' escapes the next byte
+                b'}' => {
+                    self.stream.read_exact(&mut byte)?;
+                    data.push(byte[0] ^ 0x20);
+                }
+                b => data.push(b),
+            }
+        }
+        let mut checksum = [0; 2];
+        self.stream.read_exact(&mut checksum)?;
+        self.stream.write_all(b"+")?;
+        Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let mut escaped = Vec::with_capacity(data.len());
+        for b in data.bytes() {
+            match b {
+                b'$' | b'#' | b'}' | b'*' => escaped.extend_from_slice(&[b'}', b ^ 0x20]),
+                _ => escaped.push(b),
+            }
+        }
+        let checksum = escaped.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        let mut packet = vec![b'$'];
+        packet.extend_from_slice(&escaped);
+        packet.extend_from_slice(format!("#{:02x}", checksum).as_bytes());
+        self.stream.write_all(&packet)
+    }
+}
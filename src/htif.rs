@@ -0,0 +1,64 @@
+// The Host-Target Interface used by riscv-tests and Spike: the guest writes
+// a command to the `tohost` doubleword in memory and the host answers
+// through `fromhost`.
+//
+// A command's top byte selects a device and the next byte a command for it.
+// Device 0 with the low bit set is an exit: 1 means every test passed, and
+// anything else is `(failing test number << 1) | 1`. Device 1 command 1
+// writes the character in the low byte to the console.
+
+use std::io::{self, Write};
+
+use crate::bus::*;
+
+const DEVICE_SYSCALL: u64 = 0;
+const DEVICE_CONSOLE: u64 = 1;
+const CONSOLE_PUTCHAR: u64 = 1;
+
+pub struct Htif {
+    pub tohost: u64,
+    pub fromhost: Option<u64>,
+}
+
+impl Htif {
+    pub fn new(tohost: u64, fromhost: Option<u64>) -> Self {
+        Self { tohost, fromhost }
+    }
+
+    // Serves a pending tohost command, if any. Returns the process exit code
+    // once the guest asks to exit: 0 for a pass, or the failing test number.
+    pub fn poll(&self, bus: &mut Bus) -> Option<i32> {
+        let command = bus.load(self.tohost, 64).ok()?;
+        if command == 0 {
+            return None;
+        }
+        // acknowledge the command so the guest can send the next one
+        let _ = bus.store(self.tohost, 64, 0);
+
+        let device = command >> 56;
+        let cmd = (command >> 48) & 0xff;
+        match (device, cmd) {
+            (DEVICE_SYSCALL, _) if command & 1 != 0 => {
+                let test = (command & 0xffff_ffff_ffff) >> 1;
+                if test != 0 {
+                    eprintln!("FAIL: test {}", test);
+                }
+                // exit statuses are 8 bits wide, so don't let a failure wrap to 0
+                Some(test.min(255) as i32)
+            }
+            (DEVICE_CONSOLE, CONSOLE_PUTCHAR) => {
+                let mut stdout = io::stdout();
+                let _ = stdout.write_all(&[command as u8]);
+                let _ = stdout.flush();
+                if let Some(fromhost) = self.fromhost {
+                    let _ = bus.store(fromhost, 64, (device << 56) | (cmd << 48));
+                }
+                None
+            }
+            _ => {
+                eprintln!("Unsupported HTIF command: {:#x}", command);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,25 @@
+// An RV64GC emulator. `Machine` bundles everything needed to load and run a
+// program; the modules it is built from are public for finer control.
+
+pub mod bus;
+pub mod clint;
+pub mod compressed;
+pub mod counters;
+pub mod cpu;
+pub mod decode;
+pub mod disasm;
+pub mod dram;
+pub mod elf;
+pub mod fpu;
+pub mod gdb;
+pub mod htif;
+pub mod machine;
+pub mod mmu;
+pub mod plic;
+pub mod pmp;
+pub mod privilege;
+pub mod trace;
+pub mod trap;
+pub mod uart;
+
+pub use machine::{Machine, StopReason};
@@ -0,0 +1,285 @@
+// A complete emulated machine: a hart, its bus and devices, and the HTIF
+// mailbox, driven one instruction at a time.
+
+use std::fmt;
+
+use crate::bus::*;
+use crate::cpu::*;
+use crate::dram::*;
+use crate::elf::*;
+use crate::htif::*;
+use crate::trap::*;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Elf(ElfError),
+    ImageTooLarge { image_size: u64, memory_size: u64 },
+    InvalidMemorySize(u64),
+    MemoryUnavailable(u64),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Elf(e) => write!(f, "{}", e),
+            LoadError::ImageTooLarge { image_size, memory_size } => {
+                write!(f, "image of {:#x} bytes does not fit in {:#x} bytes of RAM", image_size, memory_size)
+            }
+            LoadError::InvalidMemorySize(size) => write!(f, "invalid RAM size {:#x}", size),
+            LoadError::MemoryUnavailable(size) => write!(f, "cannot allocate {:#x} bytes of RAM", size),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<ElfError> for LoadError {
+    fn from(e: ElfError) -> Self {
+        LoadError::Elf(e)
+    }
+}
+
+// Why a machine stopped running.
+#[derive(Debug)]
+pub enum StopReason {
+    // The guest jumped to address 0, the usual end of a bare-metal program.
+    Halted,
+    // The guest exited through HTIF with this process exit code: 0 for a
+    // pass, or the number of the failing test.
+    Exited(i32),
+    // The guest raised an exception it cannot recover from, or any exception
+    // at all when `stop_on_trap` is set. `instruction` holds the raw bits at
+    // `pc` when they could be fetched.
+    Trap {
+        exception: Exception,
+        pc: u64,
+        instruction: Option<u64>,
+    },
+    // The condition given to `run_until` was met.
+    Condition,
+}
+
+pub struct Machine {
+    pub cpu: Cpu,
+    pub htif: Option<Htif>,
+    // stop at the first exception instead of taking the trap
+    pub stop_on_trap: bool,
+}
+
+impl Machine {
+    // A machine with `memory_size` bytes of zeroed RAM at DRAM_BASE, where
+    // it starts running.
+    pub fn new(memory_size: u64) -> Result<Self, LoadError> {
+        if memory_size == 0 || DRAM_BASE.checked_add(memory_size).is_none() {
+            return Err(LoadError::InvalidMemorySize(memory_size));
+        }
+        // allocating RAM aborts the process if the host cannot provide it,
+        // so find out first
+        if Vec::<u8>::new().try_reserve_exact(memory_size as usize).is_err() {
+            return Err(LoadError::MemoryUnavailable(memory_size));
+        }
+        Ok(Self {
+            cpu: Cpu::new(memory_size),
+            htif: None,
+            stop_on_trap: false,
+        })
+    }
+
+    pub fn memory_size(&self) -> u64 {
+        self.bus().device::<Dram>().map_or(0, |dram| dram.size())
+    }
+
+    // Copies a flat binary to the start of RAM.
+    pub fn load_binary(&mut self, binary: &[u8]) -> Result<(), LoadError> {
+        let memory_size = self.memory_size();
+        match self.cpu.bus.device_mut::<Dram>() {
+            Some(dram) if binary.len() as u64 <= memory_size => {
+                dram.dram[..binary.len()].copy_from_slice(binary);
+                Ok(())
+            }
+            _ => Err(LoadError::ImageTooLarge {
+                image_size: binary.len() as u64,
+                memory_size,
+            }),
+        }
+    }
+
+    // Loads an ELF executable and moves pc to its entry point. HTIF is
+    // enabled if the file has a `tohost` symbol.
+    pub fn load_elf(&mut self, bytes: &[u8]) -> Result<(), LoadError> {
+        let image = Elf::parse(bytes)?;
+        image.load(&mut self.cpu.bus)?;
+        self.cpu.pc = image.entry;
+        if let Some(tohost) = image.symbols.get("tohost") {
+            self.htif = Some(Htif::new(*tohost, image.symbols.get("fromhost").copied()));
+        }
+        Ok(())
+    }
+
+    // Loads `bytes` as an ELF executable if it is one, or as a flat binary.
+    pub fn load_image(&mut self, bytes: &[u8]) -> Result<(), LoadError> {
+        if is_elf(bytes) {
+            self.load_elf(bytes)
+        } else {
+            self.load_binary(bytes)
+        }
+    }
+
+    // Executes one instruction, taking any trap it raises. Returns why the
+    // machine stopped, if it did.
+    pub fn step(&mut self) -> Option<StopReason> {
+        if let Err(exception) = self.cpu.step() {
+            let pc = self.cpu.pc;
+            if self.stop_on_trap || exception.is_fatal() {
+                let instruction = self.cpu.fetch().ok();
+                if !self.stop_on_trap {
+                    // still take the trap so the CSRs show what happened
+                    exception.handle_trap(&mut self.cpu, pc);
+                }
+                return Some(StopReason::Trap { exception, pc, instruction });
+            }
+            exception.handle_trap(&mut self.cpu, pc);
+        }
+        if let Some(code) = self.htif.as_ref().and_then(|htif| htif.poll(&mut self.cpu.bus)) {
+            return Some(StopReason::Exited(code));
+        }
+        if self.cpu.pc == 0 {
+            return Some(StopReason::Halted);
+        }
+        None
+    }
+
+    // Runs until the machine stops or `condition` holds after an instruction.
+    pub fn run_until(&mut self, mut condition: impl FnMut(&Machine) -> bool) -> StopReason {
+        loop {
+            if let Some(reason) = self.step() {
+                return reason;
+            }
+            if condition(self) {
+                return StopReason::Condition;
+            }
+        }
+    }
+
+    // Runs until the machine stops by itself.
+    pub fn run(&mut self) -> StopReason {
+        self.run_until(|_| false)
+    }
+
+    pub fn pc(&self) -> u64 {
+        self.cpu.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u64) {
+        self.cpu.pc = pc;
+    }
+
+    pub fn reg(&self, reg: usize) -> u64 {
+        self.cpu.registers[reg]
+    }
+
+    // Writes an integer register; writes to x0 are ignored.
+    pub fn set_reg(&mut self, reg: usize, value: u64) {
+        if reg != 0 {
+            self.cpu.registers[reg] = value;
+        }
+    }
+
+    pub fn freg(&self, reg: usize) -> u64 {
+        self.cpu.fregs[reg]
+    }
+
+    pub fn set_freg(&mut self, reg: usize, value: u64) {
+        self.cpu.fregs[reg] = value;
+    }
+
+    pub fn csr(&self, csr: usize) -> u64 {
+        self.cpu.load_csr(csr)
+    }
+
+    pub fn set_csr(&mut self, csr: usize, value: u64) {
+        self.cpu.store_csr(csr, value);
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.cpu.curr_mode
+    }
+
+    pub fn bus(&self) -> &Bus {
+        &self.cpu.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        &mut self.cpu.bus
+    }
+
+    // Reads `size` bits at physical address `addr`.
+    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        self.cpu.bus.load(addr, size)
+    }
+
+    // Writes `size` bits at physical address `addr`.
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        self.cpu.bus.store(addr, size, value)
+    }
+
+    // Fills `data` from physical memory starting at `addr`.
+    pub fn read_memory(&mut self, addr: u64, data: &mut [u8]) -> Result<(), Exception> {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = self.cpu.bus.load(addr + i as u64, 8)? as u8;
+        }
+        Ok(())
+    }
+
+    // Copies `data` to physical memory starting at `addr`.
+    pub fn write_memory(&mut self, addr: u64, data: &[u8]) -> Result<(), Exception> {
+        for (i, byte) in data.iter().enumerate() {
+            self.cpu.bus.store(addr + i as u64, 8, *byte as u64)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clint::*;
+
+    const WFI: u32 = 0x10500073;
+
+    fn mtime(machine: &Machine) -> u64 {
+        machine.bus().device::<Clint>().unwrap().mtime()
+    }
+
+    // A hart waiting on a timer set to u64::MAX, which means off, must not
+    // jump mtime there: the next tick would wrap it and the hart would spin.
+    #[test]
+    fn wfi_with_the_timer_off_does_not_skip_time() {
+        let mut machine = Machine::new(4096).unwrap();
+        machine.load_binary(&WFI.to_le_bytes()).unwrap();
+        machine.store(CLINT_MTIMECMP, 64, u64::MAX).unwrap();
+        machine.set_csr(MIE, MIP_MTIP);
+        for _ in 0..10 {
+            assert!(machine.step().is_none());
+            assert!(mtime(&machine) <= 10);
+        }
+        assert!(machine.cpu.waiting);
+    }
+
+    #[test]
+    fn wfi_skips_to_the_timer_deadline() {
+        // near or far, any deadline short of u64::MAX is reached at once
+        for deadline in [5000, 1 << 60] {
+            let mut machine = Machine::new(4096).unwrap();
+            machine.load_binary(&WFI.to_le_bytes()).unwrap();
+            machine.store(CLINT_MTIMECMP, 64, deadline).unwrap();
+            machine.set_csr(MIE, MIP_MTIP);
+            machine.step();
+            machine.step();
+            assert_eq!(mtime(&machine), deadline);
+            // the next step sees the timer pending and wakes the hart
+            machine.step();
+            assert!(!machine.cpu.waiting);
+        }
+    }
+}
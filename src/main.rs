@@ -2,65 +2,195 @@ use std::io::{self, Read};
 use std::env;
 use std::fs::File;
 
-mod cpu;
-mod dram; 
-mod bus;
-mod trap;
-mod plic;
-mod clint;
-use crate::cpu::*;
-use crate::trap::*;
+use rvemu::{Machine, StopReason};
+use rvemu::{clint, cpu, disasm, dram, elf, gdb, htif, trace, uart};
 
 
+// Parses an address given in hex with a 0x prefix, or in decimal.
+fn parse_addr(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+// Parses a byte count with an optional K, M or G suffix.
+fn parse_size(text: &str) -> Option<u64> {
+    let (digits, shift) = match text.char_indices().last()? {
+        (i, 'K' | 'k') => (&text[..i], 10),
+        (i, 'M' | 'm') => (&text[..i], 20),
+        (i, 'G' | 'g') => (&text[..i], 30),
+        _ => (text, 0),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+// Lists the executable segments of an ELF file, or a whole flat binary as
+// it would be placed in DRAM.
+fn disassemble_file(filename: &str) -> io::Result<()> {
+    let code = std::fs::read(filename)?;
+    if !elf::is_elf(&code) {
+        disasm::dump(&code, dram::DRAM_BASE);
+        return Ok(());
+    }
+    match elf::Elf::parse(&code) {
+        Ok(image) => {
+            for segment in image.segments.iter().filter(|segment| segment.executable) {
+                disasm::dump(&segment.data, segment.paddr);
+            }
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("{}: {}", filename, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Exits with `code` once the trace, if any, is written out.
+fn exit(machine: &mut Machine, code: i32) -> ! {
+    if let Some(mut trace) = machine.cpu.trace.take()
+        && let Err(e) = trace.flush()
+    {
+        eprintln!("trace: {}", e);
+    }
+    std::process::exit(code);
+}
+
 fn main() -> io::Result<()>{
     let args: Vec<String> = env::args().collect();
-    if args.len() < 2 || args.len() > 3{
-        eprintln!("Usage: rvemu <filename> [--no-trap]");
+    let usage = || -> ! {
+        eprintln!("Usage: rvemu <filename> [--no-trap] [--gdb <port>] [--trace <file>]");
+        eprintln!("             [--tohost <addr>] [--fromhost <addr>] [--memory <size>[K|M|G]]");
+        eprintln!("             [--misaligned emulate|trap] [--timebase <hz>]");
+        eprintln!("       rvemu disasm <filename>");
         std::process::exit(1);
+    };
+    if args.len() > 1 && args[1] == "disasm" {
+        if args.len() != 3 {
+            usage();
+        }
+        return disassemble_file(&args[2]);
+    }
+    let mut filename = None;
+    let mut no_trap = false;
+    let mut gdb_port = None;
+    let mut trace_path = None;
+    let mut tohost = None;
+    let mut fromhost = None;
+    let mut memory_size = dram::DRAM_SIZE;
+    let mut misaligned = cpu::MisalignedPolicy::Emulate;
+    let mut clock = clint::Clock::Instructions;
+    let mut options = args.iter().skip(1);
+    while let Some(arg) = options.next() {
+        match arg.as_str() {
+            "--no-trap" => no_trap = true,
+            "--gdb" => match options.next().and_then(|port| port.parse::<u16>().ok()) {
+                Some(port) => gdb_port = Some(port),
+                None => usage(),
+            },
+            "--trace" => match options.next() {
+                Some(path) => trace_path = Some(path),
+                None => usage(),
+            },
+            "--tohost" => match options.next().and_then(|addr| parse_addr(addr)) {
+                Some(addr) => tohost = Some(addr),
+                None => usage(),
+            },
+            "--fromhost" => match options.next().and_then(|addr| parse_addr(addr)) {
+                Some(addr) => fromhost = Some(addr),
+                None => usage(),
+            },
+            "--memory" => match options.next().and_then(|size| parse_size(size)) {
+                Some(size) => memory_size = size,
+                None => usage(),
+            },
+            "--misaligned" => match options.next().map(|policy| policy.as_str()) {
+                Some("emulate") => misaligned = cpu::MisalignedPolicy::Emulate,
+                Some("trap") => misaligned = cpu::MisalignedPolicy::Trap,
+                _ => usage(),
+            },
+            "--timebase" => match options.next().and_then(|hz| hz.parse::<u64>().ok()) {
+                Some(frequency) if frequency > 0 => clock = clint::Clock::WallClock { frequency },
+                _ => usage(),
+            },
+            _ if filename.is_none() && !arg.starts_with("--") => filename = Some(arg),
+            _ => usage(),
+        }
     }
-    let filename = &args[1];
-    let no_trap = args.len() == 3 && args[2] == "--no-trap";
+    let Some(filename) = filename else { usage() };
     let mut file = File::open(filename)?;
     let mut code: Vec<u8> = Vec::new();
     file.read_to_end(&mut code)?;
-    let mut cpu = Cpu::new(code);
-    loop{
-            let instruction = match cpu.fetch(){
-                Ok(instruction) => instruction,
-                Err(exception) => {
-                    if no_trap {
-                        break;
-                    }
-                    exception.handle_trap(&mut cpu);
-                    if exception.is_fatal() {
-                        break;
-                    }
-                    0 
-                }
-            };
-            
-            cpu.pc += 4;
-
-            match cpu.execute(instruction){
-                Ok(_) => {},
-                Err(exception) => {
-                    if no_trap {
-                        break;
-                    }
-                    exception.handle_trap(&mut cpu);
-                    if exception.is_fatal() {
-                        break;
-                    }
-                }
+    let mut machine = match Machine::new(memory_size) {
+        Ok(machine) => machine,
+        Err(e) => {
+            eprintln!("--memory: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = machine.load_image(&code) {
+        eprintln!("{}: {}", filename, e);
+        std::process::exit(1);
+    }
+    machine.stop_on_trap = no_trap;
+    machine.cpu.misaligned = misaligned;
+    if let Some(clint) = machine.bus_mut().device_mut::<clint::Clint>() {
+        clint.set_clock(clock);
+    }
+    if let Some(uart) = machine.bus_mut().device_mut::<uart::Uart>() {
+        uart.attach_stdin();
+    }
+    // addresses given on the command line override the ELF symbols
+    let elf_htif = machine.htif.take();
+    let tohost = tohost.or(elf_htif.as_ref().map(|htif| htif.tohost));
+    let fromhost = fromhost.or(elf_htif.and_then(|htif| htif.fromhost));
+    machine.htif = tohost.map(|tohost| htif::Htif::new(tohost, fromhost));
+    if let Some(path) = trace_path {
+        match trace::Trace::create(path) {
+            Ok(trace) => machine.cpu.trace = Some(trace),
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                std::process::exit(1);
             }
+        }
+    }
 
-            if cpu.pc == 0{
-                break;
+    let mut finished = None;
+    if let Some(port) = gdb_port {
+        let ended = gdb::GdbStub::listen(port).and_then(|mut stub| stub.serve(&mut machine));
+        match ended {
+            Ok(gdb::SessionEnd::Detached) => {}
+            Ok(gdb::SessionEnd::Killed) => return Ok(()),
+            Ok(gdb::SessionEnd::Finished(reason)) => finished = Some(reason),
+            Err(e) => {
+                eprintln!("gdb: {}", e);
+                exit(&mut machine, 1);
             }
+        }
+    }
+    let reason = finished.unwrap_or_else(|| machine.run());
+
+    if let StopReason::Trap { exception, pc, instruction } = &reason {
+        let inst = instruction.map_or("?".to_string(), disasm::disassemble);
+        eprintln!("{:?} at {:#x}: {}", exception, pc, inst);
     }
-    cpu.dump_registers();
+    machine.cpu.dump_registers();
     println!("-----------------------------------------------------------------------------------------------------------");
-    cpu.dump_csrs();
+    machine.cpu.dump_csrs();
+    // a program stopped by a trap failed, and so did an HTIF program that
+    // stopped without reporting a result
+    let code = match reason {
+        StopReason::Exited(code) => code,
+        StopReason::Trap { .. } => 1,
+        StopReason::Halted if machine.htif.is_some() => {
+            eprintln!("halted without reporting a result through tohost");
+            1
+        }
+        StopReason::Halted | StopReason::Condition => 0,
+    };
+    if code != 0 {
+        exit(&mut machine, code);
+    }
     Ok(())
 }
-
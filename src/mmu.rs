@@ -0,0 +1,153 @@
+use crate::cpu::*;
+use crate::trap::*;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+//satp modes
+pub const SATP_MODE_SV39: u64 = 8;
+pub const SATP_MODE_SV48: u64 = 9;
+
+//mstatus fields consulted during translation
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+pub const MSTATUS_SUM: u64 = 1 << 18;
+pub const MSTATUS_MXR: u64 = 1 << 19;
+
+//page table entry bits
+pub const PTE_V: u64 = 1 << 0;
+pub const PTE_R: u64 = 1 << 1;
+pub const PTE_W: u64 = 1 << 2;
+pub const PTE_X: u64 = 1 << 3;
+pub const PTE_U: u64 = 1 << 4;
+pub const PTE_A: u64 = 1 << 6;
+pub const PTE_D: u64 = 1 << 7;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum AccessType {
+    Instruction,
+    Load,
+    Store,
+}
+
+impl AccessType {
+    fn page_fault(self, vaddr: u64) -> Exception {
+        match self {
+            AccessType::Instruction => Exception::InstructionPageFault(vaddr),
+            AccessType::Load => Exception::LoadPageFault(vaddr),
+            AccessType::Store => Exception::StoreAMOPageFault(vaddr),
+        }
+    }
+
+    pub fn access_fault(self, vaddr: u64) -> Exception {
+        match self {
+            AccessType::Instruction => Exception::InstructionAccessFault(vaddr),
+            AccessType::Load => Exception::LoadAccessFault(vaddr),
+            AccessType::Store => Exception::StoreAMOAccessFault(vaddr),
+        }
+    }
+}
+
+impl Cpu {
+    // The privilege level memory accesses are checked against; loads and
+    // stores from M-mode use MPP instead when mstatus.MPRV is set.
+    pub fn effective_mode(&self, access: AccessType) -> Mode {
+        let mstatus = self.load_csr(MSTATUS);
+        if access != AccessType::Instruction && self.curr_mode == Mode::Machine && mstatus & MSTATUS_MPRV != 0 {
+            return Mode::from_mpp(mstatus >> 11);
+        }
+        self.curr_mode
+    }
+
+    // Translates a virtual address through the page tables selected by satp.
+    pub fn translate(&mut self, vaddr: u64, access: AccessType) -> Result<u64, Exception> {
+        let mode = self.effective_mode(access);
+        self.walk(vaddr, access, mode, false)
+    }
+
+    // Translates an address the way the current mode sees it, for a
+    // debugger. MPRV, PMP and page permissions are ignored, and the A/D bits
+    // are left alone, so inspecting memory changes nothing in the guest.
+    pub fn debug_translate(&mut self, vaddr: u64, access: AccessType) -> Result<u64, Exception> {
+        self.walk(vaddr, access, self.curr_mode, true)
+    }
+
+    fn walk(&mut self, vaddr: u64, access: AccessType, mode: Mode, debug: bool) -> Result<u64, Exception> {
+        let satp = self.load_csr(SATP);
+        if mode == Mode::Machine {
+            return Ok(vaddr);
+        }
+        let levels = match satp >> 60 {
+            SATP_MODE_SV39 => 3,
+            SATP_MODE_SV48 => 4,
+            _ => return Ok(vaddr),
+        };
+
+        // the bits above the virtual address width must copy its top bit
+        let va_bits = 12 + 9 * levels;
+        let top = (vaddr as i64) >> (va_bits - 1);
+        if top != 0 && top != -1 {
+            return Err(access.page_fault(vaddr));
+        }
+
+        let mut table = (satp & 0xfff_ffff_ffff) * PAGE_SIZE;
+        let mut level = levels - 1;
+        let (pte, pte_addr) = loop {
+            let vpn = (vaddr >> (12 + 9 * level)) & 0x1ff;
+            let pte_addr = table + vpn * 8;
+            // the walk reads page tables with S-mode's PMP permissions
+            if !debug && !self.pmp.allows(pte_addr, 8, AccessType::Load, Mode::Supervisor) {
+                return Err(access.access_fault(vaddr));
+            }
+            let pte = self.bus.load(pte_addr, 64).map_err(|_| access.access_fault(vaddr))?;
+            // reserved high bits must be zero; W without R is reserved too
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) || pte >> 54 != 0 {
+                return Err(access.page_fault(vaddr));
+            }
+            if pte & (PTE_R | PTE_X) != 0 {
+                break (pte, pte_addr);
+            }
+            if level == 0 {
+                return Err(access.page_fault(vaddr));
+            }
+            level -= 1;
+            table = ((pte >> 10) & 0xfff_ffff_ffff) * PAGE_SIZE;
+        };
+
+        let mstatus = self.load_csr(MSTATUS);
+        let permitted = match access {
+            AccessType::Instruction => pte & PTE_X != 0,
+            AccessType::Load => pte & PTE_R != 0 || (mstatus & MSTATUS_MXR != 0 && pte & PTE_X != 0),
+            AccessType::Store => pte & PTE_W != 0,
+        };
+        let user_ok = match mode {
+            Mode::User => pte & PTE_U != 0,
+            // S-mode never executes user pages and reaches their data only with SUM
+            Mode::Supervisor => pte & PTE_U == 0 || (access != AccessType::Instruction && mstatus & MSTATUS_SUM != 0),
+            Mode::Machine => true,
+        };
+        if !debug && (!permitted || !user_ok) {
+            return Err(access.page_fault(vaddr));
+        }
+
+        let ppn = (pte >> 10) & 0xfff_ffff_ffff;
+        // a superpage must be aligned to its own size
+        let offset_bits = 12 + 9 * level;
+        let low_ppn_mask = (1 << (9 * level)) - 1;
+        if ppn & low_ppn_mask != 0 {
+            return Err(access.page_fault(vaddr));
+        }
+
+        // update the accessed and dirty bits in place
+        let mut updated = pte | PTE_A;
+        if access == AccessType::Store {
+            updated |= PTE_D;
+        }
+        if !debug && updated != pte {
+            if !self.pmp.allows(pte_addr, 8, AccessType::Store, Mode::Supervisor) {
+                return Err(access.access_fault(vaddr));
+            }
+            self.bus.store(pte_addr, 64, updated).map_err(|_| access.access_fault(vaddr))?;
+        }
+
+        Ok(((ppn << 12) & !((1 << offset_bits) - 1)) | (vaddr & ((1 << offset_bits) - 1)))
+    }
+}
@@ -1,71 +1,239 @@
 use crate::trap::*;
 use crate::bus::*;
 
+// A platform-level interrupt controller laid out like SiFive's and QEMU's
+// virt board. Each hart has two contexts, M-mode (2 * hart) and S-mode
+// (2 * hart + 1), with their own enable bits, priority threshold and
+// claim/complete register.
+//
+// Sources are level-triggered. A source is pending while its line is high,
+// except that once claimed it stays quiet until the claim is completed.
+
+pub const PLIC_SOURCES: usize = 1024;
+pub const PLIC_MAX_PRIORITY: u32 = 7;
+
+//register blocks, each indexed by source or context
+pub const PLIC_PRIORITY: u64 = PLIC_BASE;
 pub const PLIC_PENDING: u64 = PLIC_BASE + 0x1000;
-pub const PLIC_SENABLE: u64 = PLIC_BASE + 0x2080;
-pub const PLIC_SPRIORITY: u64 = PLIC_BASE + 0x201000;
-pub const PLIC_SCLAIM: u64 = PLIC_BASE + 0x201004;
+pub const PLIC_ENABLE: u64 = PLIC_BASE + 0x2000;
+pub const PLIC_ENABLE_STRIDE: u64 = 0x80;
+pub const PLIC_THRESHOLD: u64 = PLIC_BASE + 0x200000;
+pub const PLIC_CLAIM: u64 = PLIC_BASE + 0x200004;
+pub const PLIC_CONTEXT_STRIDE: u64 = 0x1000;
+
+const WORDS: usize = PLIC_SOURCES / 32;
+
+fn bit(map: &[u32], irq: usize) -> bool {
+    map[irq / 32] & (1 << (irq % 32)) != 0
+}
+
+fn set_bit(map: &mut [u32], irq: usize, value: bool) {
+    if value {
+        map[irq / 32] |= 1 << (irq % 32);
+    } else {
+        map[irq / 32] &= !(1 << (irq % 32));
+    }
+}
 
 pub struct Plic {
-    pending: u64,
-    senable: u64,
-    spriority: u64,
-    sclaim: u64,
+    priority: Vec<u32>,
+    // the level of each source's line
+    level: [u32; WORDS],
+    pending: [u32; WORDS],
+    // sources claimed and not yet completed
+    claimed: [u32; WORDS],
+    // per context
+    enable: Vec<[u32; WORDS]>,
+    threshold: Vec<u32>,
+    // whether each context's interrupt output is asserted
+    interrupting: Vec<bool>,
 }
 
 impl Device for Plic {
-    fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
-        if size == 32 {
-            match addr {
-                PLIC_PENDING => {
-                    return Ok(self.pending);
-                }
-                PLIC_SENABLE => {
-                    return Ok(self.senable);
-                }
-                PLIC_SPRIORITY => {
-                    return Ok(self.spriority);
-                }
-                PLIC_SCLAIM => {
-                    return Ok(self.sclaim);
-                }
-                _ => {
-                    return Ok(0);
-                }
+    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if size != 32 {
+            return Err(Exception::LoadAccessFault(addr));
+        }
+        let value = match addr {
+            PLIC_PRIORITY..PLIC_PENDING => self.priority.get(Self::index(addr, PLIC_PRIORITY)).copied().unwrap_or(0),
+            PLIC_PENDING..PLIC_ENABLE => self.pending.get(Self::index(addr, PLIC_PENDING)).copied().unwrap_or(0),
+            PLIC_ENABLE..PLIC_THRESHOLD => {
+                let (context, word) = Self::enable_index(addr);
+                self.enable.get(context).and_then(|enable| enable.get(word)).copied().unwrap_or(0)
             }
-        }   
-        Err(Exception::LoadAccessFault)
+            _ => match Self::context_register(addr) {
+                Some((context, PLIC_THRESHOLD)) if context < self.contexts() => self.threshold[context],
+                Some((context, PLIC_CLAIM)) if context < self.contexts() => self.claim(context),
+                _ => 0,
+            },
+        };
+        Ok(value as u64)
     }
+
     fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        if size == 32 {
-            match addr {
-                PLIC_PENDING => {
-                    self.pending = value;
-                }
-                PLIC_SENABLE => {
-                    self.senable = value
+        if size != 32 {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
+        let value = value as u32;
+        match addr {
+            PLIC_PRIORITY..PLIC_PENDING => {
+                let irq = Self::index(addr, PLIC_PRIORITY);
+                // source 0 does not exist
+                if (1..PLIC_SOURCES).contains(&irq) {
+                    self.priority[irq] = value.min(PLIC_MAX_PRIORITY);
                 }
-                PLIC_SPRIORITY => {
-                    self.spriority = value;
+            }
+            // pending bits follow the sources and cannot be written
+            PLIC_PENDING..PLIC_ENABLE => {}
+            PLIC_ENABLE..PLIC_THRESHOLD => {
+                let (context, word) = Self::enable_index(addr);
+                if context < self.contexts() && word < WORDS {
+                    // source 0's enable bit is hardwired to zero
+                    self.enable[context][word] = if word == 0 { value & !1 } else { value };
                 }
-                PLIC_SCLAIM => {
-                    self.sclaim = value
+            }
+            _ => match Self::context_register(addr) {
+                Some((context, PLIC_THRESHOLD)) if context < self.contexts() => {
+                    self.threshold[context] = value.min(PLIC_MAX_PRIORITY);
                 }
+                Some((context, PLIC_CLAIM)) if context < self.contexts() => self.complete(context, value as usize),
                 _ => {}
-            }
-            return Ok(());
-        }   
-        Err(Exception::StoreAMOAccessFault)
+            },
+        }
+        self.update();
+        Ok(())
+    }
+}
+
+impl Default for Plic {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl Plic{
+    // A PLIC for a single hart.
     pub fn new() -> Self {
+        Self::with_harts(1)
+    }
+
+    pub fn with_harts(harts: usize) -> Self {
+        let contexts = 2 * harts;
         Self {
-            pending: 0,
-            senable: 0,
-            spriority: 0,
-            sclaim: 0,
+            priority: vec![0; PLIC_SOURCES],
+            level: [0; WORDS],
+            pending: [0; WORDS],
+            claimed: [0; WORDS],
+            enable: vec![[0; WORDS]; contexts],
+            threshold: vec![0; contexts],
+            interrupting: vec![false; contexts],
         }
     }
-}
\ No newline at end of file
+
+    pub fn contexts(&self) -> usize {
+        self.threshold.len()
+    }
+
+    pub fn m_context(hart: usize) -> usize {
+        2 * hart
+    }
+
+    pub fn s_context(hart: usize) -> usize {
+        2 * hart + 1
+    }
+
+    // The 32-bit register index of `addr` within the block at `base`.
+    fn index(addr: u64, base: u64) -> usize {
+        ((addr - base) / 4) as usize
+    }
+
+    // The context and word an address in the enable block refers to.
+    fn enable_index(addr: u64) -> (usize, usize) {
+        let offset = addr - PLIC_ENABLE;
+        ((offset / PLIC_ENABLE_STRIDE) as usize, ((offset % PLIC_ENABLE_STRIDE) / 4) as usize)
+    }
+
+    // The context and register (PLIC_THRESHOLD or PLIC_CLAIM) an address in
+    // the per-context block refers to.
+    fn context_register(addr: u64) -> Option<(usize, u64)> {
+        let offset = addr.checked_sub(PLIC_THRESHOLD)?;
+        let context = (offset / PLIC_CONTEXT_STRIDE) as usize;
+        Some((context, PLIC_THRESHOLD + offset % PLIC_CONTEXT_STRIDE))
+    }
+
+    // Drives source `irq`'s line. Lines beyond the last source are ignored.
+    pub fn set_irq(&mut self, irq: u32, level: bool) {
+        let irq = irq as usize;
+        if irq == 0 || irq >= PLIC_SOURCES || bit(&self.level, irq) == level {
+            return;
+        }
+        set_bit(&mut self.level, irq, level);
+        if !bit(&self.claimed, irq) {
+            set_bit(&mut self.pending, irq, level);
+            self.update();
+        }
+    }
+
+    pub fn raise_irq(&mut self, irq: u32) {
+        self.set_irq(irq, true);
+    }
+
+    pub fn lower_irq(&mut self, irq: u32) {
+        self.set_irq(irq, false);
+    }
+
+    // Whether context `context` has an enabled source pending above its
+    // threshold.
+    pub fn is_interrupting(&self, context: usize) -> bool {
+        self.interrupting.get(context).copied().unwrap_or(false)
+    }
+
+    // The pending source `context` would claim: the enabled one with the
+    // highest priority above the threshold, the lowest ID breaking ties.
+    fn best_source(&self, context: usize) -> Option<usize> {
+        let mut best: Option<usize> = None;
+        for word in 0..WORDS {
+            let mut candidates = self.pending[word] & self.enable[context][word];
+            while candidates != 0 {
+                let irq = word * 32 + candidates.trailing_zeros() as usize;
+                candidates &= candidates - 1;
+                let priority = self.priority[irq];
+                if priority > self.threshold[context] && best.is_none_or(|best| priority > self.priority[best]) {
+                    best = Some(irq);
+                }
+            }
+        }
+        best
+    }
+
+    // Claims the best pending source for `context`, returning its ID, or 0
+    // if there is none.
+    fn claim(&mut self, context: usize) -> u32 {
+        let Some(irq) = self.best_source(context) else {
+            return 0;
+        };
+        set_bit(&mut self.pending, irq, false);
+        set_bit(&mut self.claimed, irq, true);
+        self.update();
+        irq as u32
+    }
+
+    // Completes a claim. IDs not enabled for the context are ignored; a
+    // source whose line is still high becomes pending again.
+    fn complete(&mut self, context: usize, irq: usize) {
+        if irq >= PLIC_SOURCES || !bit(&self.enable[context], irq) {
+            return;
+        }
+        set_bit(&mut self.claimed, irq, false);
+        if bit(&self.level, irq) {
+            set_bit(&mut self.pending, irq, true);
+        }
+    }
+
+    // Recomputes every context's interrupt output.
+    fn update(&mut self) {
+        for context in 0..self.contexts() {
+            self.interrupting[context] = self.best_source(context).is_some();
+        }
+    }
+}
@@ -0,0 +1,325 @@
+// Physical memory protection: 64 entries, each a pmpaddr CSR and a byte of
+// pmpcfg, that grant read, write and execute permission on physical address
+// ranges. The lowest-numbered entry matching an access decides it. Entries
+// only bind M-mode once locked, and a locked entry cannot be changed until
+// reset.
+//
+// As the spec requires, an S-mode or U-mode access that matches no entry
+// fails, so firmware must grant access to memory before leaving M-mode.
+
+use crate::cpu::*;
+use crate::mmu::*;
+use crate::trap::*;
+
+pub const PMP_ENTRIES: usize = 64;
+
+//PMP CSRs; on RV64 only the even pmpcfg registers exist, eight entries each
+pub const PMPCFG0: usize = 0x3a0;
+pub const PMPCFG15: usize = 0x3af;
+pub const PMPADDR0: usize = 0x3b0;
+pub const PMPADDR63: usize = 0x3ef;
+
+//pmpcfg fields
+pub const PMP_R: u8 = 1 << 0;
+pub const PMP_W: u8 = 1 << 1;
+pub const PMP_X: u8 = 1 << 2;
+pub const PMP_A: u8 = 0b11 << 3;
+pub const PMP_L: u8 = 1 << 7;
+
+//address-matching modes
+pub const PMP_OFF: u8 = 0;
+pub const PMP_TOR: u8 = 1;
+pub const PMP_NA4: u8 = 2;
+pub const PMP_NAPOT: u8 = 3;
+
+// pmpaddr holds bits 55:2 of a 56-bit physical address
+const PMPADDR_MASK: u64 = (1 << 54) - 1;
+
+pub struct Pmp {
+    cfg: [u8; PMP_ENTRIES],
+    addr: [u64; PMP_ENTRIES],
+    // whether any entry is locked
+    locked: bool,
+}
+
+impl Default for Pmp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pmp {
+    pub fn new() -> Self {
+        Self {
+            cfg: [0; PMP_ENTRIES],
+            addr: [0; PMP_ENTRIES],
+            locked: false,
+        }
+    }
+
+    fn mode(&self, entry: usize) -> u8 {
+        (self.cfg[entry] & PMP_A) >> 3
+    }
+
+    fn is_locked(&self, entry: usize) -> bool {
+        self.cfg[entry] & PMP_L != 0
+    }
+
+    // Reads pmpcfg<n>, which packs the configuration of entries 4n to 4n + 7.
+    // The odd-numbered registers do not exist and read as zero.
+    pub fn read_cfg(&self, n: usize) -> u64 {
+        if n % 2 == 1 {
+            return 0;
+        }
+        (0..8).fold(0, |value, i| value | (self.cfg[4 * n + i] as u64) << (8 * i))
+    }
+
+    pub fn write_cfg(&mut self, n: usize, value: u64) {
+        if n % 2 == 1 {
+            return;
+        }
+        for i in 0..8 {
+            let entry = 4 * n + i;
+            if self.is_locked(entry) {
+                continue;
+            }
+            // bits 6:5 are reserved, and W without R is reserved too
+            let mut cfg = (value >> (8 * i)) as u8 & !0x60;
+            if cfg & PMP_R == 0 {
+                cfg &= !PMP_W;
+            }
+            self.cfg[entry] = cfg;
+        }
+        self.locked = (0..PMP_ENTRIES).any(|entry| self.is_locked(entry));
+    }
+
+    pub fn read_addr(&self, entry: usize) -> u64 {
+        self.addr[entry]
+    }
+
+    // Writes pmpaddr<entry> unless the entry is locked, or the next entry is
+    // a locked TOR range using it as its base.
+    pub fn write_addr(&mut self, entry: usize, value: u64) {
+        let next_locked_tor = entry + 1 < PMP_ENTRIES && self.is_locked(entry + 1) && self.mode(entry + 1) == PMP_TOR;
+        if !self.is_locked(entry) && !next_locked_tor {
+            self.addr[entry] = value & PMPADDR_MASK;
+        }
+    }
+
+    // The byte range [start, end) an entry covers, or None if it is off.
+    fn range(&self, entry: usize) -> Option<(u64, u64)> {
+        let addr = self.addr[entry];
+        match self.mode(entry) {
+            PMP_TOR => {
+                let base = if entry == 0 { 0 } else { self.addr[entry - 1] << 2 };
+                Some((base, addr << 2))
+            }
+            PMP_NA4 => Some((addr << 2, (addr << 2) + 4)),
+            PMP_NAPOT => {
+                // the trailing ones give the size: 2^(ones + 3) bytes
+                let mask = addr ^ (addr + 1);
+                Some(((addr & !mask) << 2, ((addr & !mask) << 2) + ((mask + 1) << 2)))
+            }
+            _ => None,
+        }
+    }
+
+    // Whether `mode` may make an access of `size` bytes at `addr`.
+    pub fn allows(&self, addr: u64, size: u64, access: AccessType, mode: Mode) -> bool {
+        if mode == Mode::Machine && !self.locked {
+            return true;
+        }
+        let end = addr.saturating_add(size);
+        for entry in 0..PMP_ENTRIES {
+            let Some((start, stop)) = self.range(entry) else {
+                continue;
+            };
+            if start >= stop || end <= start || addr >= stop {
+                continue;
+            }
+            // an access straddling the edge of the matching entry fails
+            if addr < start || end > stop {
+                return false;
+            }
+            if mode == Mode::Machine && !self.is_locked(entry) {
+                return true;
+            }
+            let permission = match access {
+                AccessType::Instruction => PMP_X,
+                AccessType::Load => PMP_R,
+                AccessType::Store => PMP_W,
+            };
+            return self.cfg[entry] & permission != 0;
+        }
+        mode == Mode::Machine
+    }
+}
+
+impl Cpu {
+    // Checks an access of `size` bits at physical address `paddr` against
+    // the PMP, at the privilege level the access is made with. A violation
+    // is an access fault reporting the virtual address `vaddr`.
+    pub fn check_pmp(&self, paddr: u64, size: u64, access: AccessType, vaddr: u64) -> Result<(), Exception> {
+        if self.pmp.allows(paddr, size / 8, access, self.effective_mode(access)) {
+            Ok(())
+        } else {
+            Err(access.access_fault(vaddr))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOAD: AccessType = AccessType::Load;
+    const STORE: AccessType = AccessType::Store;
+    const FETCH: AccessType = AccessType::Instruction;
+
+    // Sets one entry's pmpaddr and then its configuration byte.
+    fn set(pmp: &mut Pmp, entry: usize, addr: u64, cfg: u8) {
+        pmp.write_addr(entry, addr);
+        let n = entry / 8 * 2;
+        let shift = 8 * (entry % 8);
+        let value = pmp.read_cfg(n) & !(0xff << shift) | (cfg as u64) << shift;
+        pmp.write_cfg(n, value);
+    }
+
+    fn cfg(mode: u8, permissions: u8) -> u8 {
+        mode << 3 | permissions
+    }
+
+    #[test]
+    fn only_machine_mode_has_access_until_an_entry_is_on() {
+        let pmp = Pmp::new();
+        assert!(pmp.allows(0x8000_0000, 8, STORE, Mode::Machine));
+        assert!(!pmp.allows(0x8000_0000, 8, LOAD, Mode::Supervisor));
+        assert!(!pmp.allows(0x8000_0000, 4, FETCH, Mode::User));
+    }
+
+    #[test]
+    fn napot_matches_a_power_of_two_range() {
+        let mut pmp = Pmp::new();
+        // 4 KiB at 0x8000_0000
+        set(&mut pmp, 0, 0x8000_0000 >> 2 | 0x1ff, cfg(PMP_NAPOT, PMP_R | PMP_X));
+        assert!(pmp.allows(0x8000_0000, 8, LOAD, Mode::User));
+        assert!(pmp.allows(0x8000_0ff8, 8, FETCH, Mode::Supervisor));
+        assert!(!pmp.allows(0x8000_0000, 8, STORE, Mode::User));
+        assert!(!pmp.allows(0x8000_1000, 1, LOAD, Mode::User));
+        assert!(!pmp.allows(0x7fff_fff8, 8, LOAD, Mode::User));
+    }
+
+    #[test]
+    fn na4_matches_four_bytes() {
+        let mut pmp = Pmp::new();
+        set(&mut pmp, 0, 0x8000_2000 >> 2, cfg(PMP_NA4, PMP_R | PMP_W));
+        assert!(pmp.allows(0x8000_2000, 4, STORE, Mode::Supervisor));
+        assert!(pmp.allows(0x8000_2003, 1, LOAD, Mode::Supervisor));
+        assert!(!pmp.allows(0x8000_2004, 4, LOAD, Mode::Supervisor));
+        assert!(!pmp.allows(0x8000_1ffc, 4, LOAD, Mode::Supervisor));
+    }
+
+    #[test]
+    fn tor_matches_from_the_previous_address() {
+        let mut pmp = Pmp::new();
+        // entry 0 is off and only supplies the base
+        set(&mut pmp, 0, 0x8000_1000 >> 2, 0);
+        set(&mut pmp, 1, 0x8000_3000 >> 2, cfg(PMP_TOR, PMP_R | PMP_W));
+        assert!(pmp.allows(0x8000_1000, 8, STORE, Mode::User));
+        assert!(pmp.allows(0x8000_2ff8, 8, STORE, Mode::User));
+        assert!(!pmp.allows(0x8000_0ff8, 8, LOAD, Mode::User));
+        assert!(!pmp.allows(0x8000_3000, 8, LOAD, Mode::User));
+        assert!(!pmp.allows(0x8000_2000, 4, FETCH, Mode::User));
+
+        // a TOR entry 0 starts at address 0
+        let mut pmp = Pmp::new();
+        set(&mut pmp, 0, 0x1000 >> 2, cfg(PMP_TOR, PMP_R));
+        assert!(pmp.allows(0, 8, LOAD, Mode::User));
+        assert!(!pmp.allows(0x1000, 8, LOAD, Mode::User));
+    }
+
+    #[test]
+    fn the_lowest_matching_entry_decides() {
+        let mut pmp = Pmp::new();
+        set(&mut pmp, 0, 0x8000_2000 >> 2, cfg(PMP_NA4, PMP_R));
+        set(&mut pmp, 1, 0x8000_0000 >> 2 | 0x7ff, cfg(PMP_NAPOT, PMP_R | PMP_W));
+        assert!(!pmp.allows(0x8000_2000, 4, STORE, Mode::Supervisor));
+        assert!(pmp.allows(0x8000_2004, 4, STORE, Mode::Supervisor));
+    }
+
+    #[test]
+    fn an_access_partly_inside_an_entry_fails() {
+        let mut pmp = Pmp::new();
+        set(&mut pmp, 0, 0x8000_2000 >> 2, cfg(PMP_NA4, PMP_R | PMP_W | PMP_X));
+        set(&mut pmp, 1, 0x8000_0000 >> 2 | 0x7ff, cfg(PMP_NAPOT, PMP_R | PMP_W | PMP_X));
+        // both halves would be allowed on their own, but entry 0 matches
+        // only half of the access
+        assert!(!pmp.allows(0x8000_1ffc, 8, LOAD, Mode::Supervisor));
+        assert!(!pmp.allows(0x8000_2000, 8, LOAD, Mode::Supervisor));
+        // M-mode is held to the same rule once entry 0 is locked
+        assert!(pmp.allows(0x8000_2000, 8, LOAD, Mode::Machine));
+        set(&mut pmp, 0, 0x8000_2000 >> 2, cfg(PMP_NA4, PMP_R | PMP_W | PMP_X) | PMP_L);
+        assert!(!pmp.allows(0x8000_2000, 8, LOAD, Mode::Machine));
+    }
+
+    #[test]
+    fn unmatched_accesses_are_allowed_only_in_machine_mode() {
+        let mut pmp = Pmp::new();
+        set(&mut pmp, 0, 0x8000_2000 >> 2, cfg(PMP_NA4, PMP_R));
+        assert!(!pmp.allows(0x8000_3000, 8, LOAD, Mode::Supervisor));
+        assert!(!pmp.allows(0x8000_3000, 8, LOAD, Mode::User));
+        assert!(pmp.allows(0x8000_3000, 8, STORE, Mode::Machine));
+    }
+
+    #[test]
+    fn a_locked_entry_binds_machine_mode() {
+        let mut pmp = Pmp::new();
+        set(&mut pmp, 0, 0x8000_2000 >> 2, cfg(PMP_NA4, PMP_R));
+        assert!(pmp.allows(0x8000_2000, 4, STORE, Mode::Machine));
+        set(&mut pmp, 0, 0x8000_2000 >> 2, cfg(PMP_NA4, PMP_R) | PMP_L);
+        assert!(pmp.allows(0x8000_2000, 4, LOAD, Mode::Machine));
+        assert!(!pmp.allows(0x8000_2000, 4, STORE, Mode::Machine));
+        assert!(!pmp.allows(0x8000_2000, 4, FETCH, Mode::Machine));
+        // an unlocked entry still does not
+        set(&mut pmp, 1, 0x8000_3000 >> 2, cfg(PMP_NA4, 0));
+        assert!(pmp.allows(0x8000_3000, 4, STORE, Mode::Machine));
+    }
+
+    #[test]
+    fn a_locked_entry_cannot_be_changed() {
+        let mut pmp = Pmp::new();
+        set(&mut pmp, 0, 0x8000_2000 >> 2, cfg(PMP_NA4, PMP_R) | PMP_L);
+        set(&mut pmp, 0, 0x8000_3000 >> 2, cfg(PMP_NAPOT, PMP_R | PMP_W | PMP_X));
+        assert_eq!(pmp.read_addr(0), 0x8000_2000 >> 2);
+        assert_eq!(pmp.read_cfg(0), (cfg(PMP_NA4, PMP_R) | PMP_L) as u64);
+    }
+
+    #[test]
+    fn a_locked_tor_entry_protects_its_base() {
+        let mut pmp = Pmp::new();
+        set(&mut pmp, 0, 0x8000_1000 >> 2, 0);
+        set(&mut pmp, 1, 0x8000_3000 >> 2, cfg(PMP_TOR, PMP_R) | PMP_L);
+        pmp.write_addr(0, 0x8000_0000 >> 2);
+        assert_eq!(pmp.read_addr(0), 0x8000_1000 >> 2);
+        // entry 0 itself is not locked, so its configuration can change
+        set(&mut pmp, 0, 0x8000_1000 >> 2, cfg(PMP_NA4, PMP_R));
+        assert_eq!(pmp.read_cfg(0) & 0xff, cfg(PMP_NA4, PMP_R) as u64);
+
+        // a locked entry that is not TOR does not protect the one below
+        let mut pmp = Pmp::new();
+        set(&mut pmp, 1, 0x8000_3000 >> 2, cfg(PMP_NA4, PMP_R) | PMP_L);
+        pmp.write_addr(0, 0x8000_0000 >> 2);
+        assert_eq!(pmp.read_addr(0), 0x8000_0000 >> 2);
+    }
+
+    #[test]
+    fn reserved_cfg_encodings_are_not_stored() {
+        let mut pmp = Pmp::new();
+        // W without R, and the reserved bits 6:5
+        pmp.write_cfg(0, (cfg(PMP_NA4, PMP_W | PMP_X) | 0x60) as u64);
+        assert_eq!(pmp.read_cfg(0), cfg(PMP_NA4, PMP_X) as u64);
+        // the odd-numbered pmpcfg registers do not exist
+        pmp.write_cfg(1, u64::MAX);
+        assert_eq!(pmp.read_cfg(1), 0);
+    }
+}
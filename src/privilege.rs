@@ -0,0 +1,277 @@
+// Privilege transitions: what taking a trap and returning from one with MRET
+// or SRET do to the current mode and the status registers, and the mstatus
+// bits (TSR, TVM, TW) that make privileged instructions illegal in S-mode.
+//
+// A trap into mode x saves the interrupt enable in xPIE and the mode it came
+// from in xPP, then clears xIE. An xRET undoes that:
+//
+//   mode <- xPP, xIE <- xPIE, xPIE <- 1, xPP <- U, and MPRV <- 0 if the new
+//   mode is not M.
+
+use crate::cpu::*;
+use crate::mmu::*;
+use crate::trap::*;
+
+//mstatus fields
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP: u64 = 1 << 8;
+pub const MSTATUS_MPP: u64 = 0b11 << 11;
+pub const MSTATUS_TVM: u64 = 1 << 20;
+pub const MSTATUS_TW: u64 = 1 << 21;
+pub const MSTATUS_TSR: u64 = 1 << 22;
+
+impl Mode {
+    // Decodes an MPP field. The reserved encoding 2 is never stored, but
+    // reads as User rather than granting privilege if it turns up.
+    pub fn from_mpp(mpp: u64) -> Mode {
+        match mpp & 0b11 {
+            3 => Mode::Machine,
+            1 => Mode::Supervisor,
+            _ => Mode::User,
+        }
+    }
+}
+
+impl Cpu {
+    // Updates mstatus for a trap taken into M-mode from the current mode.
+    pub fn enter_machine_trap(&mut self) {
+        let mut mstatus = self.load_csr(MSTATUS);
+        if mstatus & MSTATUS_MIE != 0 {
+            mstatus |= MSTATUS_MPIE;
+        } else {
+            mstatus &= !MSTATUS_MPIE;
+        }
+        mstatus &= !(MSTATUS_MIE | MSTATUS_MPP);
+        mstatus |= (self.curr_mode as u64) << 11;
+        self.store_csr(MSTATUS, mstatus);
+        self.curr_mode = Mode::Machine;
+    }
+
+    // Updates sstatus for a trap taken into S-mode from the current mode,
+    // which is S or U.
+    pub fn enter_supervisor_trap(&mut self) {
+        let mut sstatus = self.load_csr(SSTATUS);
+        if sstatus & MSTATUS_SIE != 0 {
+            sstatus |= MSTATUS_SPIE;
+        } else {
+            sstatus &= !MSTATUS_SPIE;
+        }
+        sstatus &= !(MSTATUS_SIE | MSTATUS_SPP);
+        if self.curr_mode == Mode::Supervisor {
+            sstatus |= MSTATUS_SPP;
+        }
+        self.store_csr(SSTATUS, sstatus);
+        self.curr_mode = Mode::Supervisor;
+    }
+
+    // MRET: only legal in M-mode.
+    pub fn mret(&mut self, instruction: u64) -> Result<(), Exception> {
+        if self.curr_mode != Mode::Machine {
+            return Err(Exception::IllegalInstruction(instruction));
+        }
+        let mut mstatus = self.load_csr(MSTATUS);
+        let mode = Mode::from_mpp(mstatus >> 11);
+        if mstatus & MSTATUS_MPIE != 0 {
+            mstatus |= MSTATUS_MIE;
+        } else {
+            mstatus &= !MSTATUS_MIE;
+        }
+        mstatus |= MSTATUS_MPIE;
+        mstatus &= !MSTATUS_MPP;
+        if mode != Mode::Machine {
+            mstatus &= !MSTATUS_MPRV;
+        }
+        self.store_csr(MSTATUS, mstatus);
+        self.curr_mode = mode;
+        self.pc = self.load_csr(MEPC);
+        Ok(())
+    }
+
+    // SRET: legal in M-mode, and in S-mode unless mstatus.TSR is set.
+    pub fn sret(&mut self, instruction: u64) -> Result<(), Exception> {
+        let mstatus = self.load_csr(MSTATUS);
+        let allowed = match self.curr_mode {
+            Mode::Machine => true,
+            Mode::Supervisor => mstatus & MSTATUS_TSR == 0,
+            Mode::User => false,
+        };
+        if !allowed {
+            return Err(Exception::IllegalInstruction(instruction));
+        }
+        let mut sstatus = self.load_csr(SSTATUS);
+        let mode = if sstatus & MSTATUS_SPP != 0 { Mode::Supervisor } else { Mode::User };
+        if sstatus & MSTATUS_SPIE != 0 {
+            sstatus |= MSTATUS_SIE;
+        } else {
+            sstatus &= !MSTATUS_SIE;
+        }
+        sstatus |= MSTATUS_SPIE;
+        sstatus &= !MSTATUS_SPP;
+        self.store_csr(SSTATUS, sstatus);
+        // SRET never returns to M-mode
+        self.store_csr(MSTATUS, self.load_csr(MSTATUS) & !MSTATUS_MPRV);
+        self.curr_mode = mode;
+        self.pc = self.load_csr(SEPC);
+        Ok(())
+    }
+
+    // SFENCE.VMA and accesses to satp: illegal in U-mode, and in S-mode
+    // while mstatus.TVM is set.
+    pub fn check_vm_access(&self, instruction: u64) -> Result<(), Exception> {
+        let allowed = match self.curr_mode {
+            Mode::Machine => true,
+            Mode::Supervisor => self.load_csr(MSTATUS) & MSTATUS_TVM == 0,
+            Mode::User => false,
+        };
+        if !allowed {
+            return Err(Exception::IllegalInstruction(instruction));
+        }
+        Ok(())
+    }
+
+    // WFI: illegal below M-mode while mstatus.TW is set, and always in
+    // U-mode. The spec allows TW to time out instead; this traps at once.
+    pub fn check_wfi(&self, instruction: u64) -> Result<(), Exception> {
+        let allowed = match self.curr_mode {
+            Mode::Machine => true,
+            Mode::Supervisor => self.load_csr(MSTATUS) & MSTATUS_TW == 0,
+            Mode::User => false,
+        };
+        if !allowed {
+            return Err(Exception::IllegalInstruction(instruction));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MRET: u64 = 0x30200073;
+    const SRET: u64 = 0x10200073;
+
+    fn hart(mode: Mode, mstatus: u64) -> Cpu {
+        let mut cpu = Cpu::new(PAGE_SIZE);
+        cpu.curr_mode = mode;
+        cpu.csregs[MSTATUS] = mstatus;
+        cpu.csregs[MEPC] = 0x8000_1000;
+        cpu.csregs[SEPC] = 0x8000_2000;
+        cpu
+    }
+
+    fn mpp(mode: Mode) -> u64 {
+        (mode as u64) << 11
+    }
+
+    #[test]
+    fn machine_trap_saves_mie_and_mode() {
+        for mode in [Mode::User, Mode::Supervisor, Mode::Machine] {
+            for (mie, mpie) in [(MSTATUS_MIE, MSTATUS_MPIE), (0, 0)] {
+                // MPIE and MPP start out opposite to what the trap leaves
+                let mut cpu = hart(mode, mie | (MSTATUS_MPIE ^ mpie) | MSTATUS_MPP);
+                cpu.enter_machine_trap();
+                assert_eq!(cpu.curr_mode, Mode::Machine);
+                let mstatus = cpu.load_csr(MSTATUS);
+                assert_eq!(mstatus & (MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP), mpie | mpp(mode), "from {:?}", mode);
+            }
+        }
+    }
+
+    #[test]
+    fn supervisor_trap_saves_sie_and_mode() {
+        for (mode, spp) in [(Mode::User, 0), (Mode::Supervisor, MSTATUS_SPP)] {
+            for (sie, spie) in [(MSTATUS_SIE, MSTATUS_SPIE), (0, 0)] {
+                let mut cpu = hart(mode, sie | (MSTATUS_SPIE ^ spie) | (MSTATUS_SPP ^ spp) | MSTATUS_MIE);
+                cpu.enter_supervisor_trap();
+                assert_eq!(cpu.curr_mode, Mode::Supervisor);
+                let mstatus = cpu.load_csr(MSTATUS);
+                assert_eq!(mstatus & (MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP), spie | spp, "from {:?}", mode);
+                // M-mode's fields are untouched
+                assert_eq!(mstatus & MSTATUS_MIE, MSTATUS_MIE);
+            }
+        }
+    }
+
+    #[test]
+    fn mret_returns_to_mpp() {
+        // the reserved MPP value 2 returns to U-mode
+        for (field, mode) in [(0, Mode::User), (1, Mode::Supervisor), (2, Mode::User), (3, Mode::Machine)] {
+            for (mpie, mie) in [(MSTATUS_MPIE, MSTATUS_MIE), (0, 0)] {
+                let mut cpu = hart(Mode::Machine, (field << 11) | mpie | MSTATUS_MPRV);
+                cpu.mret(MRET).unwrap();
+                assert_eq!(cpu.curr_mode, mode, "MPP = {}", field);
+                assert_eq!(cpu.pc, 0x8000_1000);
+                let mprv = if mode == Mode::Machine { MSTATUS_MPRV } else { 0 };
+                let mstatus = cpu.load_csr(MSTATUS);
+                assert_eq!(
+                    mstatus & (MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP | MSTATUS_MPRV),
+                    mie | MSTATUS_MPIE | mprv,
+                    "MPP = {}",
+                    field
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn sret_returns_to_spp() {
+        for caller in [Mode::Supervisor, Mode::Machine] {
+            for (spp, mode) in [(0, Mode::User), (MSTATUS_SPP, Mode::Supervisor)] {
+                for (spie, sie) in [(MSTATUS_SPIE, MSTATUS_SIE), (0, 0)] {
+                    let mut cpu = hart(caller, spp | spie | MSTATUS_MPRV);
+                    cpu.sret(SRET).unwrap();
+                    assert_eq!(cpu.curr_mode, mode);
+                    assert_eq!(cpu.pc, 0x8000_2000);
+                    let mstatus = cpu.load_csr(MSTATUS);
+                    assert_eq!(
+                        mstatus & (MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_MPRV),
+                        sie | MSTATUS_SPIE,
+                        "SRET from {:?} to {:?}",
+                        caller,
+                        mode
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn xret_from_a_lower_mode_is_illegal() {
+        for mode in [Mode::User, Mode::Supervisor] {
+            let mut cpu = hart(mode, mpp(Mode::Machine));
+            assert_eq!(cpu.mret(MRET), Err(Exception::IllegalInstruction(MRET)));
+            assert_eq!(cpu.curr_mode, mode);
+        }
+        let mut cpu = hart(Mode::User, MSTATUS_SPP);
+        assert_eq!(cpu.sret(SRET), Err(Exception::IllegalInstruction(SRET)));
+        assert_eq!(cpu.curr_mode, Mode::User);
+    }
+
+    #[test]
+    fn tsr_traps_sret_in_supervisor_mode() {
+        let mut cpu = hart(Mode::Supervisor, MSTATUS_TSR);
+        assert_eq!(cpu.sret(SRET), Err(Exception::IllegalInstruction(SRET)));
+        let mut cpu = hart(Mode::Machine, MSTATUS_TSR);
+        assert_eq!(cpu.sret(SRET), Ok(()));
+    }
+
+    #[test]
+    fn tvm_and_tw_trap_in_supervisor_mode() {
+        const SFENCE_VMA: u64 = 0x12000073;
+        const WFI: u64 = 0x10500073;
+        for mode in [Mode::User, Mode::Supervisor, Mode::Machine] {
+            for trap in [0, MSTATUS_TVM | MSTATUS_TW] {
+                let cpu = hart(mode, trap);
+                // U-mode never has either; M-mode ignores both bits
+                let allowed = mode == Mode::Machine || (mode == Mode::Supervisor && trap == 0);
+                let result = |allowed: bool, instruction| {
+                    if allowed { Ok(()) } else { Err(Exception::IllegalInstruction(instruction)) }
+                };
+                assert_eq!(cpu.check_vm_access(SFENCE_VMA), result(allowed, SFENCE_VMA), "{:?}, {:#x}", mode, trap);
+                assert_eq!(cpu.check_wfi(WFI), result(allowed, WFI), "{:?}, {:#x}", mode, trap);
+            }
+        }
+    }
+}
@@ -0,0 +1,131 @@
+// Logs every instruction the Cpu executes in the format Spike prints with
+// `-l --log-commits`, so a run can be diffed line by line against Spike's.
+// Each instruction gets a disassembly line, and those that retire also get a
+// commit line listing the registers, CSRs and memory they wrote or read:
+//
+//   core   0: 0x0000000080000000 (0x00000297) auipc   t0, 0x0
+//   core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::compressed::*;
+use crate::cpu::*;
+use crate::decode;
+use crate::disasm::*;
+
+pub struct Trace {
+    out: BufWriter<File>,
+    pc: u64,
+    instruction: u64,
+    mode: Mode,
+    freg: Option<(usize, u64)>,
+    csrs: Vec<(usize, u64)>,
+    loads: Vec<u64>,
+    // (address, size in bits, value)
+    stores: Vec<(u64, u64, u64)>,
+}
+
+// The integer register an instruction writes, if any.
+fn int_dest(instruction: u64) -> Option<usize> {
+    let inst = if is_compressed(instruction) { expand(instruction).ok()? } else { instruction };
+    let rd = decode::rd(inst);
+    let writes = match decode::opcode(inst) {
+        0x03 | 0x13 | 0x17 | 0x1b | 0x2f | 0x33 | 0x37 | 0x3b | 0x67 | 0x6f => true,
+        0x73 => decode::funct3(inst) != 0,
+        // comparisons, conversions to integer, fmv.x and fclass
+        0x53 => matches!(decode::funct7(inst) >> 2, 0x14 | 0x18 | 0x1c),
+        _ => false,
+    };
+    (writes && rd != 0).then_some(rd)
+}
+
+// Hex with as many digits as the value has bits / 4, as Spike prints values.
+fn hex(value: u64, bits: u64) -> String {
+    format!("0x{:0width$x}", value, width = (bits / 4) as usize)
+}
+
+impl Trace {
+    pub fn create(path: &str) -> io::Result<Self> {
+        Ok(Self {
+            out: BufWriter::new(File::create(path)?),
+            pc: 0,
+            instruction: 0,
+            mode: Mode::Machine,
+            freg: None,
+            csrs: Vec::new(),
+            loads: Vec::new(),
+            stores: Vec::new(),
+        })
+    }
+
+    // Writes out everything logged so far. Dropping the trace does this too,
+    // but process::exit skips drops.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    // Starts recording the effects of the instruction about to execute.
+    pub fn begin(&mut self, pc: u64, instruction: u64, mode: Mode) {
+        self.pc = pc;
+        self.instruction = instruction;
+        self.mode = mode;
+        self.freg = None;
+        self.csrs.clear();
+        self.loads.clear();
+        self.stores.clear();
+    }
+
+    pub fn freg_write(&mut self, reg: usize, value: u64) {
+        self.freg = Some((reg, value));
+    }
+
+    pub fn csr_write(&mut self, csr: usize, value: u64) {
+        self.csrs.push((csr, value));
+    }
+
+    pub fn load(&mut self, addr: u64) {
+        self.loads.push(addr);
+    }
+
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) {
+        self.stores.push((addr, size, value & (u64::MAX >> (64 - size))));
+    }
+
+    // Writes the lines for the instruction. One that trapped only gets its
+    // disassembly, as in Spike.
+    pub fn end(&mut self, registers: &[u64; 32], retired: bool) {
+        let encoding = if is_compressed(self.instruction) {
+            hex(self.instruction, 16)
+        } else {
+            hex(self.instruction, 32)
+        };
+        let mut line = format!(
+            "core   0: {} ({}) {}\n",
+            hex(self.pc, 64),
+            encoding,
+            disassemble(self.instruction)
+        );
+        if retired {
+            line += &format!("core   0: {} {} ({})", self.mode as u64, hex(self.pc, 64), encoding);
+            if let Some(rd) = int_dest(self.instruction) {
+                line += &format!(" x{:<2} {}", rd, hex(registers[rd], 64));
+            }
+            if let Some((rd, value)) = self.freg {
+                line += &format!(" f{:<2} {}", rd, hex(value, 64));
+            }
+            for (csr, value) in &self.csrs {
+                line += &format!(" c{}_{} {}", csr, csr_name(*csr), hex(*value, 64));
+            }
+            for addr in &self.loads {
+                line += &format!(" mem {}", hex(*addr, 64));
+            }
+            for (addr, size, value) in &self.stores {
+                line += &format!(" mem {} {}", hex(*addr, 64), hex(*value, *size));
+            }
+            line += "\n";
+        }
+        // the trace is best-effort; a full disk should not stop the guest
+        let _ = self.out.write_all(line.as_bytes());
+    }
+}
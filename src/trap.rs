@@ -1,70 +1,104 @@
 #![allow(dead_code, unused_variables)]
 use crate::cpu::*;
 
+// Synchronous exceptions. Each one carries the value written to mtval/stval
+// when it is taken: the faulting virtual address for misaligned, access and
+// page faults and for breakpoints, and the instruction bits for illegal
+// instructions.
+#[derive(Debug, PartialEq, Eq)]
 pub enum Exception{
-    InstructionAddressMisaligned,
-    InstructionAccessFault,
-    IllegalInstruction,
-    Breakpoint,
-    LoadAddressMisaligned,
-    LoadAccessFault,
-    StoreAMOAddressMisaligned,
-    StoreAMOAccessFault,
+    InstructionAddressMisaligned(u64),
+    InstructionAccessFault(u64),
+    IllegalInstruction(u64),
+    Breakpoint(u64),
+    LoadAddressMisaligned(u64),
+    LoadAccessFault(u64),
+    StoreAMOAddressMisaligned(u64),
+    StoreAMOAccessFault(u64),
     EnvironmentCallFromUMode,
     EnvironmentCallFromSMode,
     EnvironmentCallFromMMode,
-    InstructionPageFault,
-    LoadPageFault,
-    StoreAMOPageFault,
+    InstructionPageFault(u64),
+    LoadPageFault(u64),
+    StoreAMOPageFault(u64),
 }
 
 impl Exception {
+    // Access faults are not fatal: guests take them to their trap handler,
+    // whether they come from unmapped memory or from the PMP.
     pub fn is_fatal(&self) -> bool{
-        match self {
-            Exception::InstructionAddressMisaligned
-            | Exception::InstructionAccessFault
-            | Exception::LoadAccessFault
-            | Exception::StoreAMOAddressMisaligned
-            | Exception::StoreAMOAccessFault => true,
-            _ => false,
-        }
+        matches!(self, Exception::InstructionAddressMisaligned(_))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Interrupt{
+    SupervisorSoftware,
+    MachineSoftware,
+    SupervisorTimer,
+    MachineTimer,
+    SupervisorExternal,
+    MachineExternal,
+}
+
+impl Interrupt {
+    // Interrupts in the order they are taken when several are pending at once.
+    pub const PRIORITY: [Interrupt; 6] = [
+        Interrupt::MachineExternal,
+        Interrupt::MachineSoftware,
+        Interrupt::MachineTimer,
+        Interrupt::SupervisorExternal,
+        Interrupt::SupervisorSoftware,
+        Interrupt::SupervisorTimer,
+    ];
+
+    // The interrupt's bit in mip/mie.
+    pub fn mask(&self) -> u64 {
+        1 << self.exception_num()
     }
 }
 
 pub trait Trap {
     fn exception_num(&self) -> u64;
 
-    fn handle_trap(&self, cpu: &mut Cpu){
-        let old_pc = cpu.pc.wrapping_sub(4);
+    fn is_interrupt(&self) -> bool {
+        false
+    }
+
+    // Value written to mtval/stval when the trap is taken.
+    fn trap_value(&self) -> u64 {
+        0
+    }
+
+    // `epc` is the address of the instruction that trapped, or of the next
+    // instruction to run when an interrupt is taken.
+    fn handle_trap(&self, cpu: &mut Cpu, epc: u64){
         let except_num = self.exception_num();
         let mode = cpu.curr_mode;
-        if (mode <= Mode::Supervisor) && ((cpu.load_csr(MEDELEG).wrapping_shr(except_num as u32)) & 1 != 0)
+        cpu.reservation = None;
+        let (deleg, cause) = match self.is_interrupt() {
+            true => (cpu.load_csr(MIDELEG), (1 << 63) | except_num),
+            false => (cpu.load_csr(MEDELEG), except_num),
+        };
+        // vectored mode sends interrupts to base + 4 * cause
+        let target = |tvec: u64| match tvec & 0b11 {
+            1 if self.is_interrupt() => (tvec & !0b11) + 4 * except_num,
+            _ => tvec & !0b11,
+        };
+        if (mode <= Mode::Supervisor) && ((deleg.wrapping_shr(except_num as u32)) & 1 != 0)
         {
-            cpu.curr_mode = Mode::Supervisor;
-            cpu.store_csr(SEPC, old_pc & !1);
-            cpu.store_csr(SCAUSE, except_num);
-            cpu.pc = cpu.load_csr(STVEC) & !1;
-            cpu.store_csr(STVAL, 0);
-            let mut sstatus = cpu.load_csr(SSTATUS);
-            if (sstatus >> 1) & 1 == 1 { sstatus |= 1 << 5; }
-            else { sstatus &= !(1 << 5); }
-            sstatus &= !(1 << 1);
-            sstatus &= !(1 << 8); 
-            if mode == Mode::Supervisor { sstatus |= 1 << 8; }
-            cpu.store_csr(SSTATUS, sstatus);
+            cpu.store_csr(SEPC, epc & !1);
+            cpu.store_csr(SCAUSE, cause);
+            cpu.pc = target(cpu.load_csr(STVEC));
+            cpu.store_csr(STVAL, self.trap_value());
+            cpu.enter_supervisor_trap();
         }
         else {
-            cpu.store_csr(MEPC, old_pc & !1);
-            cpu.store_csr(MCAUSE, except_num);
-            cpu.pc = cpu.load_csr(MTVEC) & !1;
-            cpu.store_csr(MTVAL, 0);
-            let mut mstatus = cpu.load_csr(MSTATUS);
-            if (mstatus >> 3) & 1 == 1 { mstatus |= 1 << 7; }
-            else { mstatus &= !(1 << 7); }
-            mstatus &= !(1 << 3);
-            mstatus &= !(0b11 << 11); 
-            mstatus |= (mode as u64) << 11;
-            cpu.store_csr(MSTATUS, mstatus);
+            cpu.store_csr(MEPC, epc & !1);
+            cpu.store_csr(MCAUSE, cause);
+            cpu.pc = target(cpu.load_csr(MTVEC));
+            cpu.store_csr(MTVAL, self.trap_value());
+            cpu.enter_machine_trap();
         }
     }
 }
@@ -72,20 +106,56 @@ pub trait Trap {
 impl Trap for Exception {
     fn exception_num(&self) -> u64 {
         match self {
-            Exception::InstructionAddressMisaligned => 0,
-            Exception::InstructionAccessFault => 1,
-            Exception::IllegalInstruction => 2,
-            Exception::Breakpoint => 3,
-            Exception::LoadAddressMisaligned => 4,
-            Exception::LoadAccessFault => 5,
-            Exception::StoreAMOAddressMisaligned => 6,
-            Exception::StoreAMOAccessFault => 7,
+            Exception::InstructionAddressMisaligned(_) => 0,
+            Exception::InstructionAccessFault(_) => 1,
+            Exception::IllegalInstruction(_) => 2,
+            Exception::Breakpoint(_) => 3,
+            Exception::LoadAddressMisaligned(_) => 4,
+            Exception::LoadAccessFault(_) => 5,
+            Exception::StoreAMOAddressMisaligned(_) => 6,
+            Exception::StoreAMOAccessFault(_) => 7,
             Exception::EnvironmentCallFromUMode => 8,
             Exception::EnvironmentCallFromSMode => 9,
             Exception::EnvironmentCallFromMMode => 11,
-            Exception::InstructionPageFault => 12,
-            Exception::LoadPageFault => 13,
-            Exception::StoreAMOPageFault => 15,
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_) => 13,
+            Exception::StoreAMOPageFault(_) => 15,
         }
     }
+
+    fn trap_value(&self) -> u64 {
+        match self {
+            Exception::InstructionAddressMisaligned(tval)
+            | Exception::InstructionAccessFault(tval)
+            | Exception::IllegalInstruction(tval)
+            | Exception::Breakpoint(tval)
+            | Exception::LoadAddressMisaligned(tval)
+            | Exception::LoadAccessFault(tval)
+            | Exception::StoreAMOAddressMisaligned(tval)
+            | Exception::StoreAMOAccessFault(tval)
+            | Exception::InstructionPageFault(tval)
+            | Exception::LoadPageFault(tval)
+            | Exception::StoreAMOPageFault(tval) => *tval,
+            Exception::EnvironmentCallFromUMode
+            | Exception::EnvironmentCallFromSMode
+            | Exception::EnvironmentCallFromMMode => 0,
+        }
+    }
+}
+
+impl Trap for Interrupt {
+    fn exception_num(&self) -> u64 {
+        match self {
+            Interrupt::SupervisorSoftware => 1,
+            Interrupt::MachineSoftware => 3,
+            Interrupt::SupervisorTimer => 5,
+            Interrupt::MachineTimer => 7,
+            Interrupt::SupervisorExternal => 9,
+            Interrupt::MachineExternal => 11,
+        }
+    }
+
+    fn is_interrupt(&self) -> bool {
+        true
+    }
 }
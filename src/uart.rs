@@ -0,0 +1,230 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+use crate::trap::*;
+use crate::bus::*;
+
+//the PLIC source the UART is wired to, as on QEMU's virt board
+pub const UART_IRQ: u32 = 10;
+
+//register offsets
+pub const UART_RBR: u64 = UART_BASE;
+pub const UART_THR: u64 = UART_BASE;
+pub const UART_IER: u64 = UART_BASE + 1;
+pub const UART_IIR: u64 = UART_BASE + 2;
+pub const UART_FCR: u64 = UART_BASE + 2;
+pub const UART_LCR: u64 = UART_BASE + 3;
+pub const UART_MCR: u64 = UART_BASE + 4;
+pub const UART_LSR: u64 = UART_BASE + 5;
+pub const UART_MSR: u64 = UART_BASE + 6;
+pub const UART_SCR: u64 = UART_BASE + 7;
+
+//register bits
+pub const UART_IER_RDI: u8 = 1 << 0;
+pub const UART_IER_THRI: u8 = 1 << 1;
+pub const UART_IIR_NO_INT: u8 = 0x01;
+pub const UART_IIR_THRI: u8 = 0x02;
+pub const UART_IIR_RDI: u8 = 0x04;
+pub const UART_IIR_FIFO: u8 = 0xc0;
+pub const UART_FCR_ENABLE: u8 = 1 << 0;
+pub const UART_LCR_DLAB: u8 = 1 << 7;
+pub const UART_MCR_LOOP: u8 = 1 << 4;
+pub const UART_LSR_DR: u8 = 1 << 0;
+pub const UART_LSR_THRE: u8 = 1 << 5;
+pub const UART_LSR_TEMT: u8 = 1 << 6;
+
+// Bytes read from host stdin by a background thread. `len` mirrors the
+// queue's length so the UART can check for input without taking the lock.
+struct HostStdin {
+    bytes: Mutex<VecDeque<u8>>,
+    len: AtomicUsize,
+}
+
+// The thread is started by the first UART attached to stdin, and shared by
+// any others.
+static STDIN: OnceLock<Arc<HostStdin>> = OnceLock::new();
+
+fn host_stdin() -> Arc<HostStdin> {
+    let stdin = STDIN.get_or_init(|| {
+        let stdin = Arc::new(HostStdin {
+            bytes: Mutex::new(VecDeque::new()),
+            len: AtomicUsize::new(0),
+        });
+        let input = Arc::clone(&stdin);
+        thread::spawn(move || {
+            let mut byte = [0; 1];
+            while let Ok(1) = io::stdin().read(&mut byte) {
+                input.bytes.lock().unwrap().push_back(byte[0]);
+                input.len.fetch_add(1, Ordering::Release);
+            }
+        });
+        stdin
+    });
+    Arc::clone(stdin)
+}
+
+// A 16550A-compatible UART. Transmitted bytes go straight to host stdout.
+// Received bytes come from `push_input`, and from host stdin once
+// `attach_stdin` is called; a new UART leaves stdin alone.
+pub struct Uart {
+    // bytes pushed by the embedder or looped back while MCR.LOOP is set
+    rx: VecDeque<u8>,
+    stdin: Option<Arc<HostStdin>>,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+    // set while a THR-empty interrupt is owed to the guest
+    thre_pending: bool,
+}
+
+impl Device for Uart {
+    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if size != 8 {
+            return Err(Exception::LoadAccessFault(addr));
+        }
+        let dlab = self.lcr & UART_LCR_DLAB != 0;
+        let value = match addr {
+            UART_RBR if dlab => self.dll,
+            UART_RBR => self.receive().unwrap_or(0),
+            UART_IER if dlab => self.dlm,
+            UART_IER => self.ier,
+            UART_IIR => {
+                let iir = self.interrupt_id();
+                // reading the THRE identification acknowledges it
+                if iir == UART_IIR_THRI {
+                    self.thre_pending = false;
+                }
+                let fifo = if self.fcr & UART_FCR_ENABLE != 0 { UART_IIR_FIFO } else { 0 };
+                iir | fifo
+            }
+            UART_LCR => self.lcr,
+            UART_MCR => self.mcr,
+            UART_LSR => {
+                let ready = if self.has_data() { UART_LSR_DR } else { 0 };
+                ready | UART_LSR_THRE | UART_LSR_TEMT
+            }
+            UART_MSR => {
+                // in loopback mode the modem outputs feed back into the status inputs
+                if self.mcr & UART_MCR_LOOP != 0 {
+                    ((self.mcr & 0x0c) << 4) | ((self.mcr & 0x01) << 5) | ((self.mcr & 0x02) << 3)
+                } else {
+                    // report CTS, DSR and DCD asserted
+                    0xb0
+                }
+            }
+            UART_SCR => self.scr,
+            _ => 0,
+        };
+        Ok(value as u64)
+    }
+
+    fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if size != 8 {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
+        let value = value as u8;
+        let dlab = self.lcr & UART_LCR_DLAB != 0;
+        match addr {
+            UART_THR if dlab => self.dll = value,
+            UART_THR => {
+                if self.mcr & UART_MCR_LOOP != 0 {
+                    self.rx.push_back(value);
+                } else {
+                    let mut stdout = io::stdout();
+                    let _ = stdout.write_all(&[value]);
+                    let _ = stdout.flush();
+                }
+                // the byte leaves at once, so THR is empty again
+                self.thre_pending = true;
+            }
+            UART_IER if dlab => self.dlm = value,
+            UART_IER => {
+                // enabling the THRE interrupt raises it straight away since THR is always empty
+                if value & UART_IER_THRI != 0 && self.ier & UART_IER_THRI == 0 {
+                    self.thre_pending = true;
+                }
+                self.ier = value & 0x0f;
+            }
+            UART_FCR => self.fcr = value,
+            UART_LCR => self.lcr = value,
+            UART_MCR => self.mcr = value & 0x1f,
+            UART_SCR => self.scr = value,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn interrupt_line(&self) -> bool {
+        self.is_interrupting()
+    }
+}
+
+impl Default for Uart {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Uart {
+    pub fn new() -> Self {
+        Self {
+            rx: VecDeque::new(),
+            stdin: None,
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            dll: 0,
+            dlm: 0,
+            thre_pending: false,
+        }
+    }
+
+    // Feeds host stdin into the receive FIFO from now on.
+    pub fn attach_stdin(&mut self) {
+        self.stdin = Some(host_stdin());
+    }
+
+    // Queues bytes for the guest to receive.
+    pub fn push_input(&mut self, bytes: &[u8]) {
+        self.rx.extend(bytes);
+    }
+
+    // The next received byte, queued bytes before those from stdin.
+    fn receive(&mut self) -> Option<u8> {
+        if let Some(byte) = self.rx.pop_front() {
+            return Some(byte);
+        }
+        let stdin = self.stdin.as_ref()?;
+        let byte = stdin.bytes.lock().unwrap().pop_front()?;
+        stdin.len.fetch_sub(1, Ordering::Release);
+        Some(byte)
+    }
+
+    fn has_data(&self) -> bool {
+        !self.rx.is_empty() || self.stdin.as_ref().is_some_and(|stdin| stdin.len.load(Ordering::Acquire) > 0)
+    }
+
+    // The highest-priority pending interrupt, as reported in IIR.
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & UART_IER_RDI != 0 && self.has_data() {
+            UART_IIR_RDI
+        } else if self.ier & UART_IER_THRI != 0 && self.thre_pending {
+            UART_IIR_THRI
+        } else {
+            UART_IIR_NO_INT
+        }
+    }
+
+    pub fn is_interrupting(&self) -> bool {
+        self.interrupt_id() != UART_IIR_NO_INT
+    }
+}